@@ -0,0 +1,185 @@
+use quicli::prelude::*;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+/// Separator between the base name of an alternate file and its conditions : `.zshrc##hostname.laptop,os.linux`
+pub(crate) const ALTERNATE_SEPARATOR: &str = "##";
+
+/// Values of the conditions used to choose between alternate source files
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conditions {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub os: Option<String>,
+    pub distro: Option<String>,
+    pub classes: Vec<String>,
+}
+
+impl Conditions {
+    /// Conditions of the current system, without class
+    pub fn current() -> Conditions {
+        let hostname = ["/proc/sys/kernel/hostname", "/etc/hostname"].iter()
+            .filter_map(|f| fs::read_to_string(f).ok())
+            .map(|h| h.trim().to_string())
+            .find(|h| !h.is_empty())
+            .or_else(|| env::var("HOSTNAME").ok());
+        let distro = fs::read_to_string("/etc/os-release").ok().and_then(|content| {
+            content.lines()
+                .find_map(|l| l.strip_prefix("ID="))
+                .map(|id| id.trim_matches('"').to_string())
+        });
+
+        Conditions {
+            hostname,
+            user: env::var("USER").ok(),
+            os: Some(env::consts::OS.to_string()),
+            distro,
+            classes: Vec::new(),
+        }
+    }
+
+    /// Score of a conditions suffix (like `hostname.laptop,os.linux`), higher for more specific
+    /// conditions. `None` if a condition doesn't match.
+    fn score(&self, conditions: &str) -> Option<u32> {
+        let mut score = 0;
+        for condition in conditions.split(',') {
+            let (key, value) = match condition.find('.') {
+                Some(i) => (&condition[..i], &condition[i + 1..]),
+                None => (condition, ""),
+            };
+            let (expected, weight) = match key {
+                "default" => continue,
+                "os" | "o" => (self.os.as_ref().map(|os| os.eq_ignore_ascii_case(value)), 1),
+                "distro" | "d" => (self.distro.as_ref().map(|distro| distro.eq_ignore_ascii_case(value)), 2),
+                "class" | "c" => (Some(self.classes.iter().any(|c| c == value)), 4),
+                "user" | "u" => (self.user.as_ref().map(|user| user == value), 8),
+                "hostname" | "h" => (self.hostname.as_ref().map(|hostname| hostname == value), 16),
+                _ => {
+                    warn!("Unknown alternate file condition {}", condition);
+                    (None, 0)
+                }
+            };
+            if expected != Some(true) {
+                return None;
+            }
+            score += weight;
+        }
+        Some(score)
+    }
+}
+
+/// Base name of an alternate file name and its conditions if any
+pub(crate) fn split_alternate(name: &str) -> (&str, Option<&str>) {
+    match name.find(ALTERNATE_SEPARATOR) {
+        Some(i) if i > 0 => (&name[..i], Some(&name[i + ALTERNATE_SEPARATOR.len()..])),
+        _ => (name, None),
+    }
+}
+
+/// Choose among entries of a directory the best alternate of each base name. A name without
+/// conditions is chosen only if no alternate matches. Returns chosen names with their base name.
+pub(crate) fn select<'a, I: IntoIterator<Item = &'a str>>(names: I, conditions: &Conditions) -> BTreeMap<&'a str, &'a str> {
+    let mut best: BTreeMap<&'a str, (u32, &'a str)> = BTreeMap::new();
+    let mut names: Vec<&'a str> = names.into_iter().collect();
+    names.sort();
+
+    for name in names {
+        let (base, suffix) = split_alternate(name);
+        let score = match suffix {
+            Some(suffix) => match conditions.score(suffix) {
+                Some(score) => score,
+                None => continue,
+            },
+            None => 0,
+        };
+        match best.get(base) {
+            Some((best_score, _)) if *best_score >= score => (),
+            _ => { best.insert(base, (score, name)); },
+        }
+    }
+
+    best.into_iter().map(|(base, (_, name))| (name, base)).collect()
+}
+
+#[cfg(test)]
+mod test_alternates {
+    use super::*;
+    use filesystem::FileSystem;
+    use operations::FSOperation;
+    use stower::Stower;
+    use test_utils::memory_source;
+    use std::path::{Path, PathBuf};
+
+    fn laptop() -> Conditions {
+        Conditions {
+            hostname: Some("laptop".to_string()),
+            user: Some("alice".to_string()),
+            os: Some("linux".to_string()),
+            distro: Some("arch".to_string()),
+            classes: vec!["work".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_split_alternate() {
+        assert_eq!(split_alternate(".zshrc##hostname.laptop"), (".zshrc", Some("hostname.laptop")));
+        assert_eq!(split_alternate(".zshrc"), (".zshrc", None));
+        assert_eq!(split_alternate("##os.linux"), ("##os.linux", None));
+    }
+
+    #[test]
+    fn test_select() {
+        let names = [".zshrc", ".zshrc##os.linux", ".zshrc##hostname.laptop", ".zshrc##hostname.server", "config##os.darwin", "config", ".vimrc##class.home"];
+        let selected = select(names.iter().cloned(), &laptop());
+
+        assert_eq!(selected.get(".zshrc##hostname.laptop"), Some(&".zshrc"));
+        assert_eq!(selected.get("config"), Some(&"config"));
+        assert_eq!(selected.len(), 2);
+
+        let selected = select(names.iter().cloned(), &Conditions { os: Some("Linux".to_string()), ..Conditions::default() });
+        assert_eq!(selected.get(".zshrc##os.linux"), Some(&".zshrc"));
+    }
+
+    #[test]
+    fn test_score() {
+        let conditions = laptop();
+        assert_eq!(conditions.score("default"), Some(0));
+        assert_eq!(conditions.score("os.linux,class.work"), Some(5));
+        assert_eq!(conditions.score("u.alice,d.arch"), Some(10));
+        assert_eq!(conditions.score("os.linux,user.bob"), None);
+        assert_eq!(conditions.score("arch.x86_64"), None);
+    }
+
+    #[test]
+    fn test_alternate_files() {
+        let fs = memory_source();
+        fs.add_file("/source/.zshrc", b"default").unwrap();
+        fs.add_file("/source/.zshrc##hostname.laptop", b"laptop").unwrap();
+        fs.add_file("/source/.zshrc##os.linux,class.work", b"work").unwrap();
+        let conditions = Conditions { hostname: Some("server".to_string()), os: Some("linux".to_string()), ..Conditions::default() };
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone()).conditions(conditions);
+        let zshrc_link = |stower: &Stower| -> Vec<FSOperation> {
+            stower.plan().unwrap().operations()
+                .filter_map(|op| op.as_ref().ok().cloned())
+                .filter(|op| op.produces() == vec![PathBuf::from("/target/.zshrc")])
+                .collect()
+        };
+
+        assert_eq!(zshrc_link(&stower), vec![FSOperation::CreateSymlink { source: PathBuf::from("/source/.zshrc"), target: PathBuf::from("/target/.zshrc") }]);
+
+        let stower = stower.class("work");
+        assert_eq!(zshrc_link(&stower), vec![FSOperation::CreateSymlink { source: PathBuf::from("/source/.zshrc##os.linux,class.work"), target: PathBuf::from("/target/.zshrc") }]);
+        assert!(stower.plan().unwrap().apply().is_empty());
+        assert!(!fs.exists(Path::new("/target/.zshrc##hostname.laptop")));
+
+        // a removed alternate file was linked to its base name
+        fs.remove_file(Path::new("/source/.zshrc##os.linux,class.work")).unwrap();
+        let plan = stower.plan_paths(&["/source/.zshrc##os.linux,class.work"]).unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![FSOperation::DeleteSymlink(PathBuf::from("/target/.zshrc"))]);
+        assert!(plan.apply().is_empty());
+        assert!(!fs.is_symlink(Path::new("/target/.zshrc")));
+    }
+}
@@ -1,67 +1,288 @@
 
-use quicli::prelude::*;
-
 use serde_derive::Deserialize;
 use toml;
-use std::io;
-use std::fs;
 
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::rc::Rc;
 use toml::value::*;
-use std::error::Error;
+use std::result::Result;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+
+use errors::{io_error, AppError, ErrorPath};
+use git::GIT_DIR_NAME;
+use filesystem::FileSystem;
 
 #[derive(Deserialize)]
 pub(crate) struct RstowConfig {
     pub symlink_current_dir: bool,
     pub ignore_files: Array,
+    /// Target names of source entries (source name -> target name)
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    /// Target paths of source paths relative to the configuration directory. Target paths are
+    /// absolute or relative to the stow target directory.
+    #[serde(default)]
+    pub map: BTreeMap<String, String>,
+    /// Required octal modes (like `"0600"`) of source paths, by path or glob pattern relative to
+    /// the configuration directory
+    #[serde(default)]
+    pub modes: BTreeMap<String, String>,
+    /// File name patterns of secrets never linked into a shared target
+    #[serde(default)]
+    pub secret_files: Vec<String>,
+    /// Secrets allowed in a shared target, by path or glob pattern relative to the configuration
+    /// directory
+    #[serde(default)]
+    pub allow_secrets: Vec<String>,
+    /// `sh` commands run in the source directory around stow and unstow, read from the root
+    /// `.rstow` file only
+    #[serde(default)]
+    pub pre_stow: Option<String>,
+    #[serde(default)]
+    pub post_stow: Option<String>,
+    #[serde(default)]
+    pub pre_unstow: Option<String>,
+    #[serde(default)]
+    pub post_unstow: Option<String>,
 }
 
 impl RstowConfig {
     pub(crate) fn default() -> RstowConfig {
         RstowConfig {
             symlink_current_dir: true,
-            ignore_files: Vec::new()
+            ignore_files: Vec::new(),
+            rename: BTreeMap::new(),
+            map: BTreeMap::new(),
+            modes: BTreeMap::new(),
+            secret_files: Vec::new(),
+            allow_secrets: Vec::new(),
+            pre_stow: None,
+            post_stow: None,
+            pre_unstow: None,
+            post_unstow: None,
         }
     }
 
+    /// Name of a source entry on target : given by a `rename` rule, otherwise with the `dot-` prefix
+    /// replaced by a `.` if `dotfiles` is set
+    pub(crate) fn target_name(config: &RstowConfig, entry: &str, dotfiles: bool) -> String {
+        match config.rename.get(entry) {
+            Some(name) => name.to_string(),
+            None if dotfiles && entry.len() > DOTFILES_PREFIX.len() && entry.starts_with(DOTFILES_PREFIX) => {
+                format!(".{}", &entry[DOTFILES_PREFIX.len()..])
+            },
+            None => entry.to_string(),
+        }
+    }
+
+    /// Target path given by a `map` rule to a path relative to the configuration directory
+    pub(crate) fn mapped_path(config: &RstowConfig, relative: &Path) -> Option<PathBuf> {
+        config.map.iter()
+            .find(|(source, _)| Path::new(source) == relative)
+            .map(|(_, target)| PathBuf::from(target))
+    }
+
+    /// Mode required by a `modes` rule for a path relative to the configuration directory. An exact
+    /// path wins over glob patterns (`*` doesn't match `/`).
+    pub(crate) fn mode(config: &RstowConfig, relative: &Path) -> Option<u32> {
+        let exact = config.modes.iter().find(|(pattern, _)| Path::new(pattern) == relative);
+        exact.or_else(|| config.modes.iter().find(|(pattern, _)| {
+            GlobBuilder::new(pattern).literal_separator(true).build()
+                .map(|g| g.compile_matcher().is_match(relative))
+                .unwrap_or(false)
+        })).and_then(|(_, mode)| parse_mode(mode))
+    }
+
+    /// A secret at a path relative to the configuration directory is allowed by `allow_secrets`
+    pub(crate) fn is_allowed_secret(config: &RstowConfig, relative: &Path) -> bool {
+        config.allow_secrets.iter().any(|pattern| {
+            Path::new(pattern) == relative || GlobBuilder::new(pattern).literal_separator(true).build()
+                .map(|g| g.compile_matcher().is_match(relative))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Hooks (name and command) run before and after operations
+    pub(crate) fn hooks(config: &RstowConfig, unstow: bool) -> (Option<Hook<'_>>, Option<Hook<'_>>) {
+        if unstow {
+            (config.pre_unstow.as_deref().map(|c| ("pre_unstow", c)), config.post_unstow.as_deref().map(|c| ("post_unstow", c)))
+        } else {
+            (config.pre_stow.as_deref().map(|c| ("pre_stow", c)), config.post_stow.as_deref().map(|c| ("post_stow", c)))
+        }
+    }
+
+    /// `ignore_files` entries are file names or glob patterns
     pub(crate) fn is_ignored(config: &RstowConfig, entry: &str) -> bool {
-        config.ignore_files.clone()
-            .into_iter()
-            .find(|i| {
-                let i_str = i.as_str().expect("Unable to read ignore_files as string");
-                i_str == entry
-            })
-            .is_some()
+        config.ignore_files.iter()
+            .filter_map(|i| i.as_str())
+            .any(|i| i == entry || Glob::new(i).map(|g| g.compile_matcher().is_match(entry)).unwrap_or(false))
     }
 }
 
+/// Name and command of a hook
+pub(crate) type Hook<'a> = (&'static str, &'a str);
+
 pub(crate) const RSTOW_FILE_NAME: &str = ".rstow";
+pub(crate) const DOTFILES_PREFIX: &str = "dot-";
+pub(crate) const GLOBAL_IGNORE_FILE_NAME: &str = ".rstow-global-ignore";
+
+/// File name patterns ignored in every source directory unless disabled
+pub(crate) const DEFAULT_IGNORE_PATTERNS: &[&str] = &[
+    ".gitignore", ".gitmodules", ".hg", ".svn", "CVS",
+    "README*", "LICENSE*", "COPYING",
+    "*.swp", "*.swo", "*~", ".#*", "#*#", ".DS_Store",
+];
+
+/// File name patterns ignored in every source directory : built-in defaults and patterns of a
+/// global ignore file. The `.rstow` configuration file is always ignored.
+#[derive(Debug)]
+pub(crate) struct IgnoreList {
+    patterns: GlobSet,
+}
+
+impl IgnoreList {
+    /// Ignore list with `DEFAULT_IGNORE_PATTERNS` (if `defaults`) and patterns of `global_file`
+    /// (one per line, `#` for comments) if it exists
+    pub(crate) fn new(fs: &dyn FileSystem, defaults: bool, global_file: Option<&Path>) -> Result<IgnoreList, AppError> {
+        let mut builder = GlobSetBuilder::new();
+        if defaults {
+            for pattern in DEFAULT_IGNORE_PATTERNS {
+                builder.add(Glob::new(pattern).expect("valid default ignore pattern"));
+            }
+        }
+
+        if let Some(file) = global_file.filter(|f| fs.exists(f)) {
+            let invalid = |cause: String| AppError::ConfigError { path: ErrorPath::from(file), cause };
+            let content = String::from_utf8_lossy(&fs.read(file).map_err(io_error(file))?).to_string();
+            for line in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                builder.add(Glob::new(line).map_err(|e| invalid(e.to_string()))?);
+            }
+        }
+
+        let patterns = builder.build().map_err(|e| AppError::ConfigError {
+            path: ErrorPath::from(Path::new(GLOBAL_IGNORE_FILE_NAME)),
+            cause: e.to_string()
+        })?;
+        Ok(IgnoreList { patterns })
+    }
+
+    /// `.rstow` files and `.git` directories are always ignored, even without default patterns
+    pub(crate) fn is_ignored(&self, entry: &str) -> bool {
+        entry == RSTOW_FILE_NAME || entry == GIT_DIR_NAME || self.patterns.is_match(entry)
+    }
+}
 
-pub(crate) fn read_config_file(directory: &Path) -> Option<RstowConfig> {
+/// Read the rstow configuration file of a directory, using default configuration if there is no file.
+/// An unreadable or malformed file is reported as an error.
+pub(crate) fn load_config(fs: &dyn FileSystem, directory: &Path) -> Result<RstowConfig, AppError> {
     let config_file = directory.join(RSTOW_FILE_NAME);
+    if !fs.exists(config_file.as_path()) {
+        return Ok(RstowConfig::default());
+    }
 
-    let content = fs::read_to_string(config_file).unwrap_or("".to_string());
-    match toml::from_str(content.as_str()) {
-        Ok(conf) => Some(conf),
-        Err(error) => {
-            debug!("Unable to read rstow configuration file in {} : {}", directory.display(), error.description());
-            None
+    let content = String::from_utf8_lossy(&fs.read(config_file.as_path()).map_err(io_error(config_file.as_path()))?).to_string();
+    let config: RstowConfig = toml::from_str(content.as_str()).map_err(|error| AppError::ConfigError {
+        path: ErrorPath::from(config_file.as_path()),
+        cause: error.to_string()
+    })?;
+
+    if config.ignore_files.iter().any(|i| i.as_str().is_none()) {
+        return Err(AppError::ConfigError {
+            path: ErrorPath::from(config_file.as_path()),
+            cause: "ignore_files must only contain strings".to_string()
+        });
+    }
+    if let Some(name) = config.rename.values().find(|n| !is_file_name(n)) {
+        return Err(AppError::ConfigError {
+            path: ErrorPath::from(config_file.as_path()),
+            cause: format!("rename target {:?} must be a file name", name)
+        });
+    }
+    if let Some((source, target)) = config.map.iter().find(|(source, target)| !is_relative_path(source) || target.is_empty()) {
+        return Err(AppError::ConfigError {
+            path: ErrorPath::from(config_file.as_path()),
+            cause: format!("invalid map rule {:?} = {:?}, source must be a relative path inside the directory", source, target)
+        });
+    }
+    if let Some((pattern, mode)) = config.modes.iter().find(|(pattern, mode)| parse_mode(mode).is_none() || Glob::new(pattern).is_err()) {
+        return Err(AppError::ConfigError {
+            path: ErrorPath::from(config_file.as_path()),
+            cause: format!("invalid mode rule {:?} = {:?}, mode must be octal like \"0600\"", pattern, mode)
+        });
+    }
+    if let Some(pattern) = config.secret_files.iter().chain(config.allow_secrets.iter()).find(|p| Glob::new(p).is_err()) {
+        return Err(AppError::ConfigError {
+            path: ErrorPath::from(config_file.as_path()),
+            cause: format!("invalid secret pattern {:?}", pattern)
+        });
+    }
+    Ok(config)
+}
+
+/// Configurations of the directories of a source tree, each `.rstow` file being loaded once
+pub(crate) struct SourceConfigs<'a> {
+    fs: &'a dyn FileSystem,
+    root: PathBuf,
+    configs: RefCell<BTreeMap<PathBuf, Rc<RstowConfig>>>,
+}
+
+impl<'a> SourceConfigs<'a> {
+    pub(crate) fn new(fs: &'a dyn FileSystem, root: &Path) -> SourceConfigs<'a> {
+        SourceConfigs { fs, root: root.to_path_buf(), configs: RefCell::new(BTreeMap::new()) }
+    }
+
+    pub(crate) fn fs(&self) -> &'a dyn FileSystem {
+        self.fs
+    }
+
+    /// Configuration of a source directory, like `load_config`. Errors are not cached.
+    pub(crate) fn get(&self, directory: &Path) -> Result<Rc<RstowConfig>, AppError> {
+        if let Some(config) = self.configs.borrow().get(directory) {
+            return Ok(config.clone());
         }
+        let config = Rc::new(load_config(self.fs, directory)?);
+        self.configs.borrow_mut().insert(directory.to_path_buf(), config.clone());
+        Ok(config)
     }
+
+    /// Parent directories of a source `path` in the source tree, nearest first
+    pub(crate) fn parents<'p>(&self, path: &'p Path) -> impl Iterator<Item = &'p Path> + 'p {
+        let root = self.root.clone();
+        path.ancestors().skip(1).take_while(move |d| d.starts_with(root.as_path()))
+    }
+}
+
+/// Octal permission bits like `0600` or `700`
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode, 8).ok().filter(|m| !mode.is_empty() && *m <= 0o7777)
 }
 
+fn is_relative_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow, clippy::bool_assert_comparison)]
 mod test_config {
     use super::*;
     use test_utils::*;
     use std::path::PathBuf;
     use std::fs::File;
+    use filesystem::{MemoryFileSystem, RealFileSystem};
+    use operations::FSOperation;
+    use stower::Stower;
     use std::io::Write;
 
     #[test]
     fn test_config_file() {
-        with_test_directories(&"test_config_file",|source: &PathBuf, target: &PathBuf| {
+        with_test_directories(&"test_config_file",|source: &PathBuf, _target: &PathBuf| {
             let mut config_file = File::create(source.as_path().join(RSTOW_FILE_NAME)).unwrap();
             let content = r#"
     symlink_current_dir = true
@@ -70,20 +291,183 @@ mod test_config {
 
             config_file.write_all(content.as_bytes()).unwrap();
 
-            let config_opt: Option<RstowConfig> = read_config_file(source.as_path());
-            assert!(config_opt.is_some());
-            let config = config_opt.unwrap();
+            let config = load_config(&RealFileSystem, source.as_path()).unwrap();
             assert_eq!(config.symlink_current_dir, true);
             let mut ignores = config.ignore_files.into_iter();
             assert_eq!(ignores.next().unwrap().as_str(), Some("secret-file.txt"));
-        });
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid_config_file() {
+        with_test_directories("test_load_invalid_config_file",|source: &PathBuf, _target: &PathBuf| {
+            let mut config_file = File::create(source.as_path().join(RSTOW_FILE_NAME)).unwrap();
+            config_file.write_all(b"ignore_files = 42").unwrap();
+
+            let config = load_config(&RealFileSystem, source.as_path());
+            assert!(config.is_err());
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_load_config_file_invalid_ignore() {
+        with_test_directories("test_load_config_file_invalid_ignore",|source: &PathBuf, _target: &PathBuf| {
+            let mut config_file = File::create(source.as_path().join(RSTOW_FILE_NAME)).unwrap();
+            config_file.write_all(b"symlink_current_dir = true\nignore_files = [ 42 ]").unwrap();
+
+            let config = load_config(&RealFileSystem, source.as_path());
+            assert!(config.is_err());
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_patterns() {
+        let config = RstowConfig { ignore_files: vec![Value::from("*.bak"), Value::from("notes.txt")], ..RstowConfig::default() };
+        assert!(RstowConfig::is_ignored(&config, "file.bak"));
+        assert!(RstowConfig::is_ignored(&config, "notes.txt"));
+        assert!(!RstowConfig::is_ignored(&config, "file.txt"));
+    }
+
+    #[test]
+    fn test_target_name() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[rename]\nvimrc = \".vimrc\"").unwrap();
+        let config = load_config(&fs, Path::new("/source")).unwrap();
+
+        assert_eq!(RstowConfig::target_name(&config, "vimrc", false), ".vimrc");
+        assert_eq!(RstowConfig::target_name(&config, "dot-bashrc", true), ".bashrc");
+        assert_eq!(RstowConfig::target_name(&config, "dot-bashrc", false), "dot-bashrc");
+        assert_eq!(RstowConfig::target_name(&config, "dot-", true), "dot-");
+
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[rename]\nvimrc = \"../.vimrc\"").unwrap();
+        assert!(load_config(&fs, Path::new("/source")).is_err());
+    }
+
+    #[test]
+    fn test_dotfiles() {
+        let fs = memory_source();
+        fs.add_file("/source/dot-config/app.conf", b"app").unwrap();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[rename]\n\"file.txt\" = \"renamed.txt\"").unwrap();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone()).dotfiles(true);
+
+        let plan = stower.plan().unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/dir"), target: PathBuf::from("/target/dir") },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/dot-config"), target: PathBuf::from("/target/.config") },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/file.txt"), target: PathBuf::from("/target/renamed.txt") },
+        ]);
+        assert!(plan.apply().is_empty());
+
+        fs.add_file("/source/dot-profile", b"profile").unwrap();
+        let plan = stower.plan_paths(&["/source/dot-profile"]).unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![FSOperation::CreateSymlink { source: PathBuf::from("/source/dot-profile"), target: PathBuf::from("/target/.profile") }]);
+        assert!(plan.apply().is_empty());
+
+        let plan = stower.unstow(true).plan().unwrap();
+        for target in ["/target/.config", "/target/renamed.txt", "/target/.profile"] {
+            assert!(plan.operations().any(|op| op.as_ref().ok() == Some(&FSOperation::DeleteSymlink(PathBuf::from(target)))));
+        }
+        assert!(plan.apply().is_empty());
+        assert!(!fs.exists(Path::new("/target/.profile")));
+    }
+
+    #[test]
+    fn test_map_rules() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[map]\n\"vscode/settings.json\" = \".config/Code/User/settings.json\"").unwrap();
+        let config = load_config(&fs, Path::new("/source")).unwrap();
+
+        assert_eq!(RstowConfig::mapped_path(&config, Path::new("vscode/settings.json")), Some(PathBuf::from(".config/Code/User/settings.json")));
+        assert_eq!(RstowConfig::mapped_path(&config, Path::new("vscode")), None);
+
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[map]\n\"../outside\" = \"file\"").unwrap();
+        assert!(load_config(&fs, Path::new("/source")).is_err());
+    }
+
+    #[test]
+    fn test_mode_rules() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[modes]\n\".ssh\" = \"0700\"\n\".ssh/*\" = \"0600\"\n\".ssh/config\" = \"644\"").unwrap();
+        let config = load_config(&fs, Path::new("/source")).unwrap();
+
+        assert_eq!(RstowConfig::mode(&config, Path::new(".ssh")), Some(0o700));
+        assert_eq!(RstowConfig::mode(&config, Path::new(".ssh/id_rsa")), Some(0o600));
+        assert_eq!(RstowConfig::mode(&config, Path::new(".ssh/config")), Some(0o644));
+        assert_eq!(RstowConfig::mode(&config, Path::new(".ssh/keys/id_rsa")), None);
+
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[modes]\n\".netrc\" = \"rw\"").unwrap();
+        assert!(load_config(&fs, Path::new("/source")).is_err());
+    }
+
+    #[test]
+    fn test_ignore_list() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/home/.rstow-global-ignore", b"# build outputs\n*.o\n\ntarget\n").unwrap();
+        let global_file = Path::new("/home/.rstow-global-ignore");
+
+        let ignores = IgnoreList::new(&fs, true, Some(global_file)).unwrap();
+        assert!(ignores.is_ignored(".rstow"));
+        assert!(ignores.is_ignored("README.md"));
+        assert!(ignores.is_ignored(".vimrc.swp"));
+        assert!(ignores.is_ignored("main.o"));
+        assert!(ignores.is_ignored("target"));
+        assert!(!ignores.is_ignored(".vimrc"));
+
+        let ignores = IgnoreList::new(&fs, false, None).unwrap();
+        assert!(ignores.is_ignored(".rstow"));
+        assert!(ignores.is_ignored(".git"));
+        assert!(!ignores.is_ignored(".gitignore"));
+        assert!(!ignores.is_ignored("README.md"));
+
+        fs.add_file("/home/.rstow-global-ignore", b"[invalid").unwrap();
+        assert!(IgnoreList::new(&fs, true, Some(global_file)).is_err());
+    }
+
+    #[test]
+    fn test_default_ignore() {
+        let fs = memory_source();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []").unwrap();
+        fs.add_file("/source/README.md", b"readme").unwrap();
+        fs.add_file("/home/.rstow-global-ignore", b"*.txt").unwrap();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+        let linked = |stower: Stower| -> Vec<PathBuf> {
+            stower.plan().unwrap().operations().map(|op| match op {
+                Ok(FSOperation::CreateSymlink { target, .. }) => target.to_path_buf(),
+                op => panic!("link expected : {:?}", op),
+            }).collect()
+        };
+
+        assert_eq!(linked(stower.clone()), vec![PathBuf::from("/target/dir"), PathBuf::from("/target/file.txt")]);
+        assert_eq!(linked(stower.clone().global_ignore("/home/.rstow-global-ignore")), vec![PathBuf::from("/target/dir")]);
+        assert_eq!(linked(stower.default_ignore(false)), vec![PathBuf::from("/target/README.md"), PathBuf::from("/target/dir"), PathBuf::from("/target/file.txt")]);
     }
 
     #[test]
     fn test_no_config_file() {
-        with_test_directories(&"test_no_config_file",|source: &PathBuf, target: &PathBuf| {
-            let config_opt: Option<RstowConfig> = read_config_file(source.as_path());
-            assert!(config_opt.is_none());
-        });
+        with_test_directories(&"test_no_config_file",|source: &PathBuf, _target: &PathBuf| {
+            let config = load_config(&RealFileSystem, source.as_path()).unwrap();
+            assert!(config.symlink_current_dir);
+            assert!(config.ignore_files.is_empty());
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_source_configs() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\nsecret_files = [\"*.kdbx\"]").unwrap();
+        fs.add_dir("/source/dir/sub").unwrap();
+        let configs = SourceConfigs::new(&fs, Path::new("/source"));
+
+        let parents: Vec<&Path> = configs.parents(Path::new("/source/dir/sub/file")).collect();
+        assert_eq!(parents, vec![Path::new("/source/dir/sub"), Path::new("/source/dir"), Path::new("/source")]);
+
+        assert_eq!(configs.get(Path::new("/source")).unwrap().secret_files, vec!["*.kdbx".to_string()]);
+        // loaded once
+        fs.add_file("/source/.rstow", b"invalid").unwrap();
+        assert_eq!(configs.get(Path::new("/source")).unwrap().secret_files, vec!["*.kdbx".to_string()]);
+        fs.add_file("/source/dir/.rstow", b"invalid").unwrap();
+        assert!(configs.get(Path::new("/source/dir")).is_err());
     }
 }
@@ -0,0 +1,137 @@
+use age::armor::ArmoredReader;
+use age::{Decryptor, Identity, IdentityFile};
+
+use std::io::Read;
+use std::path::Path;
+use std::result::Result;
+
+use errors::{io_error, AppError, ErrorPath};
+use filesystem::FileSystem;
+
+/// Suffix of source files encrypted with age, decrypted on target without it
+pub(crate) const ENCRYPTED_SUFFIX: &str = ".age";
+
+/// Permission bits of decrypted files
+pub(crate) const DECRYPTED_MODE: u32 = 0o600;
+
+/// Name of the decrypted file of an encrypted source file name, `None` if not encrypted
+pub(crate) fn decrypted_name(name: &str) -> Option<&str> {
+    name.strip_suffix(ENCRYPTED_SUFFIX).filter(|n| !n.is_empty())
+}
+
+/// Decrypt an age file (binary or armored) with the identities (`AGE-SECRET-KEY-...` lines)
+/// of an `identity` file
+pub(crate) fn decrypt(fs: &dyn FileSystem, identity: &Path, source: &Path) -> Result<Vec<u8>, AppError> {
+    let error = |path: &Path, cause: String| AppError::DecryptError { path: ErrorPath::from(path), cause };
+
+    let identities = IdentityFile::from_buffer(&fs.read(identity).map_err(io_error(identity))?[..])
+        .map_err(|e| error(identity, e.to_string()))?
+        .into_identities()
+        .map_err(|e| error(identity, e.to_string()))?;
+
+    let encrypted = fs.read(source).map_err(io_error(source))?;
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(&encrypted[..])).map_err(|e| error(source, e.to_string()))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))
+        .map_err(|e| error(source, e.to_string()))?;
+
+    let mut decrypted = Vec::new();
+    reader.read_to_end(&mut decrypted).map_err(|e| error(source, e.to_string()))?;
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod test_crypto {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use age::x25519;
+    use errors::{AppError, MODIFIED_DECRYPTED_HINT};
+    use filesystem::MemoryFileSystem;
+    use operations::FSOperation;
+    use stower::Stower;
+    use test_utils::memory_source;
+    use std::path::PathBuf;
+
+    /// Add an identity file and a file encrypted for it
+    fn add_encrypted_file(fs: &MemoryFileSystem, identity: &str, path: &str, content: &[u8]) {
+        let key = x25519::Identity::generate();
+        fs.add_file(identity, format!("# test key\n{}\n", key.to_string().expose_secret()).as_bytes()).unwrap();
+        fs.add_file(path, &age::encrypt(&key.to_public(), content).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_decrypted_name() {
+        assert_eq!(decrypted_name(".netrc.age"), Some(".netrc"));
+        assert_eq!(decrypted_name(".age"), None);
+        assert_eq!(decrypted_name(".netrc"), None);
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let fs = MemoryFileSystem::new();
+        add_encrypted_file(&fs, "/keys/identity.txt", "/source/.netrc.age", b"machine example.org");
+
+        assert_eq!(decrypt(&fs, Path::new("/keys/identity.txt"), Path::new("/source/.netrc.age")).unwrap(), b"machine example.org".to_vec());
+
+        add_encrypted_file(&fs, "/keys/other.txt", "/source/other.age", b"");
+        assert!(decrypt(&fs, Path::new("/keys/identity.txt"), Path::new("/source/other.age")).is_err());
+        assert!(decrypt(&fs, Path::new("/keys/identity.txt"), Path::new("/keys/other.txt")).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_files() {
+        let fs = memory_source();
+        add_encrypted_file(&fs, "/keys/identity.txt", "/source/secrets/dot-netrc.age", b"machine example.org");
+        fs.add_file("/source/secrets/notes.txt", b"notes").unwrap();
+        let stower = Stower::new("/source", "/target").dotfiles(true).filesystem(fs.clone());
+        let secret_operations = |stower: &Stower| -> Vec<Result<FSOperation, AppError>> {
+            stower.plan().unwrap().operations()
+                .filter(|op| op.as_ref().map(|op| op.produces().iter().chain(op.removes().iter()).any(|p| p.starts_with("/target/secrets"))).unwrap_or(true))
+                .cloned()
+                .collect()
+        };
+
+        // no identity
+        match secret_operations(&stower).as_slice() {
+            [Ok(FSOperation::CreateDir(_)), Err(AppError::DecryptError { path, .. }), Ok(FSOperation::CreateSymlink { .. })] => assert_eq!(path.as_path(), Path::new("/source/secrets/dot-netrc.age")),
+            operations => panic!("decrypt error expected : {:?}", operations),
+        }
+
+        // directory of an encrypted file is created, the file is decrypted
+        let stower = stower.identity("/keys/identity.txt");
+        let operations: Vec<FSOperation> = secret_operations(&stower).into_iter().map(|op| op.unwrap()).collect();
+        assert_eq!(operations, vec![
+            FSOperation::CreateDir(PathBuf::from("/target/secrets")),
+            FSOperation::Decrypt { source: PathBuf::from("/source/secrets/dot-netrc.age"), target: PathBuf::from("/target/secrets/.netrc"), identity: PathBuf::from("/keys/identity.txt") },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/secrets/notes.txt"), target: PathBuf::from("/target/secrets/notes.txt") },
+        ]);
+        assert!(stower.plan().unwrap().apply().is_empty());
+        assert_eq!(fs.read(Path::new("/target/secrets/.netrc")).unwrap(), b"machine example.org".to_vec());
+        assert_eq!(fs.mode(Path::new("/target/secrets/.netrc")).unwrap(), 0o600);
+
+        // decrypted file up to date
+        assert!(stower.plan().unwrap().operations().all(|op| matches!(op, Ok(FSOperation::Nothing { .. }))));
+
+        // a modified decrypted file is a conflict unless forced
+        fs.add_file("/target/secrets/.netrc", b"modified").unwrap();
+        let plan = stower.clone().unstow(true).plan().unwrap();
+        let error = plan.errors().find(|e| e.path() == Some(Path::new("/target/secrets/.netrc"))).unwrap();
+        assert!(matches!(error, AppError::StowPathError { hint: MODIFIED_DECRYPTED_HINT, .. }));
+        let operations: Vec<FSOperation> = secret_operations(&stower.clone().force(true)).into_iter().map(|op| op.unwrap()).collect();
+        assert_eq!(operations, vec![
+            FSOperation::DeleteFile(PathBuf::from("/target/secrets/.netrc")),
+            FSOperation::Decrypt { source: PathBuf::from("/source/secrets/dot-netrc.age"), target: PathBuf::from("/target/secrets/.netrc"), identity: PathBuf::from("/keys/identity.txt") },
+        ]);
+        assert!(stower.clone().force(true).plan().unwrap().apply().is_empty());
+
+        let plan = stower.clone().unstow(true).plan().unwrap();
+        assert!(plan.operations().any(|op| op.as_ref().ok() == Some(&FSOperation::SecureDelete(PathBuf::from("/target/secrets/.netrc")))));
+        assert!(plan.apply().is_empty());
+        assert!(!fs.exists(Path::new("/target/secrets/.netrc")));
+
+        // a path created after planning is a conflict, a link is not followed
+        let plan = stower.plan().unwrap();
+        fs.symlink(Path::new("/source/secrets/notes.txt"), Path::new("/target/secrets/.netrc")).unwrap();
+        assert!(plan.apply().errors().iter().any(|e| matches!(e, AppError::StowPathError { .. }) && e.path() == Some(Path::new("/target/secrets/.netrc"))));
+        assert_eq!(fs.read(Path::new("/source/secrets/notes.txt")).unwrap(), b"notes".to_vec());
+    }
+}
@@ -1,4 +1,7 @@
 
+// impls generated by the `Fail` derive of failure 0.1 are not local
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::path::{Path, PathBuf};
 use std::clone::Clone;
@@ -6,32 +9,236 @@ use std::fmt::*;
 use std::ops::Deref;
 use std::io;
 
+/// Errors found when planning or applying operations
 #[derive(Fail, Debug, Clone)]
-pub(crate) enum AppError {
+pub enum AppError {
     #[fail(display = "Unable to stow {} to {} cause : {}", source, target, cause)]
     StowPathError {
         source: ErrorPath,
         target: ErrorPath,
-        cause: String
+        cause: String,
+        /// Suggestion fixing this cause, see `AppError::suggestion`
+        hint: &'static str
     },
 
-    #[fail(display = "An IO error append : {}", msg)]
+    #[fail(display = "An IO error append on {} : {}", path, cause)]
     IOError {
-        msg: String
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Invalid rstow configuration file {} : {}", path, cause)]
+    ConfigError {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Invalid path {} : {}", path, cause)]
+    InvalidPath {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "File name of {} is not a valid UTF-8 string", path)]
+    NonUtf8Name {
+        path: ErrorPath
+    },
+
+    #[fail(display = "Target of symbolic link {} not found", path)]
+    MissingTarget {
+        path: ErrorPath
+    },
+
+    #[fail(display = "Invalid operation plan on {} : {}", path, cause)]
+    InvalidPlan {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Unable to read git repository {} : {}", path, cause)]
+    GitError {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Refusing to link secret {} into shared target {} : {}", source, target, cause)]
+    SecretError {
+        source: ErrorPath,
+        target: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Unable to lock {} : {}", path, cause)]
+    LockError {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Unable to decrypt {} : {}", path, cause)]
+    DecryptError {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Invalid permissions of {} deployed to {} : {}", source, target, cause)]
+    ModeError {
+        source: ErrorPath,
+        target: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Path {} changed since planning : {}", path, cause)]
+    PathChanged {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Target {} differs from source : {}", path, cause)]
+    DriftError {
+        path: ErrorPath,
+        cause: String
+    },
+
+    #[fail(display = "Hook {} of {} failed : {}", hook, path, cause)]
+    HookError {
+        path: ErrorPath,
+        hook: String,
+        cause: String
     },
 
     #[fail(display = "Unable to apply stow because of previous errors")]
     ApplyError
 }
 
+/// Error categories used to summarize errors and choose the process exit code
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum ErrorType {
+    Conflict,
+    IO,
+    Config,
+}
 
-impl From<io::Error> for AppError {
-    fn from(io: io::Error) -> Self {
-        AppError::IOError { msg: io.to_string()}
+impl ErrorType {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorType::Conflict => 1,
+            ErrorType::IO => 2,
+            ErrorType::Config => 3,
+        }
     }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ErrorType::Conflict => "conflict(s)",
+            ErrorType::IO => "IO error(s)",
+            ErrorType::Config => "configuration error(s)",
+        }
+    }
+}
+
+impl AppError {
+    pub fn error_type(&self) -> ErrorType {
+        match self {
+            AppError::StowPathError { .. } => ErrorType::Conflict,
+            AppError::IOError { .. } => ErrorType::IO,
+            AppError::ConfigError { .. } => ErrorType::Config,
+            AppError::InvalidPath { .. } => ErrorType::Config,
+            AppError::NonUtf8Name { .. } => ErrorType::Conflict,
+            AppError::MissingTarget { .. } => ErrorType::Conflict,
+            AppError::InvalidPlan { .. } => ErrorType::Conflict,
+            AppError::GitError { .. } => ErrorType::Config,
+            AppError::SecretError { .. } => ErrorType::Conflict,
+            AppError::LockError { .. } => ErrorType::Conflict,
+            AppError::DecryptError { .. } => ErrorType::Config,
+            AppError::ModeError { .. } => ErrorType::Config,
+            AppError::PathChanged { .. } => ErrorType::Conflict,
+            AppError::DriftError { .. } => ErrorType::Conflict,
+            AppError::HookError { .. } => ErrorType::IO,
+            AppError::ApplyError => ErrorType::Conflict,
+        }
+    }
+
+    /// Path concerned by the error if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            AppError::StowPathError { target, .. } => Some(target.as_path()),
+            AppError::ConfigError { path, .. } => Some(path.as_path()),
+            AppError::InvalidPath { path, .. } => Some(path.as_path()),
+            AppError::NonUtf8Name { path } => Some(path.as_path()),
+            AppError::MissingTarget { path } => Some(path.as_path()),
+            AppError::InvalidPlan { path, .. } => Some(path.as_path()),
+            AppError::GitError { path, .. } => Some(path.as_path()),
+            AppError::SecretError { target, .. } => Some(target.as_path()),
+            AppError::LockError { path, .. } => Some(path.as_path()),
+            AppError::DecryptError { path, .. } => Some(path.as_path()),
+            AppError::ModeError { target, .. } => Some(target.as_path()),
+            AppError::PathChanged { path, .. } => Some(path.as_path()),
+            AppError::DriftError { path, .. } => Some(path.as_path()),
+            AppError::HookError { path, .. } => Some(path.as_path()),
+            AppError::IOError { path, .. } => Some(path.as_path()),
+            AppError::ApplyError => None,
+        }
+    }
+
+    /// Reason of the error without the concerned path
+    pub fn reason(&self) -> String {
+        match self {
+            AppError::StowPathError { cause, .. } => cause.to_string(),
+            AppError::ConfigError { cause, .. } => cause.to_string(),
+            AppError::InvalidPath { cause, .. } => cause.to_string(),
+            AppError::NonUtf8Name { .. } => "File name is not a valid UTF-8 string".to_string(),
+            AppError::MissingTarget { .. } => "Target of symbolic link not found".to_string(),
+            AppError::InvalidPlan { cause, .. } => cause.to_string(),
+            AppError::GitError { cause, .. } => cause.to_string(),
+            AppError::SecretError { source, cause, .. } => format!("Secret {} : {}", source, cause),
+            AppError::LockError { cause, .. } => cause.to_string(),
+            AppError::DecryptError { cause, .. } => cause.to_string(),
+            AppError::ModeError { source, cause, .. } => format!("Source {} : {}", source, cause),
+            AppError::PathChanged { cause, .. } => cause.to_string(),
+            AppError::DriftError { cause, .. } => cause.to_string(),
+            AppError::HookError { hook, cause, .. } => format!("Hook {} failed : {}", hook, cause),
+            AppError::IOError { cause, .. } => cause.to_string(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Hint given to the user to fix the error
+    pub fn suggestion(&self) -> Option<&'static str> {
+        match self {
+            AppError::StowPathError { hint, .. } => Some(hint),
+            AppError::ConfigError { .. } => Some("fix or remove the .rstow file"),
+            AppError::InvalidPath { .. } => Some("check that the path exists and is accessible"),
+            AppError::NonUtf8Name { .. } => Some("rename the file with a valid UTF-8 name"),
+            AppError::MissingTarget { .. } => Some("remove the dangling symbolic link or use --force"),
+            AppError::InvalidPlan { .. } => Some("please report this issue"),
+            AppError::GitError { .. } => Some("check the git repository with `git status` or stow without --git"),
+            AppError::SecretError { .. } => Some("encrypt the secret, stow it to a private target or allow it with allow_secrets in .rstow"),
+            AppError::LockError { .. } => Some("wait for the other rstow run to finish or increase --lock-timeout"),
+            AppError::DecryptError { .. } => Some("check the age identity file given with --identity"),
+            AppError::ModeError { .. } => Some("restrict permissions of the source file with chmod"),
+            AppError::PathChanged { .. } => Some("the target was changed by another program, run rstow again"),
+            AppError::DriftError { .. } => Some("stow again with --force (add --backup to keep target changes) or copy target changes into source"),
+            AppError::HookError { .. } => Some("fix the hook command in the .rstow file of the source directory"),
+            AppError::IOError { .. } => Some("check permissions and free space on target"),
+            AppError::ApplyError => None,
+        }
+    }
+}
+
+/// Hints of `StowPathError`s, depending on the flags able to resolve the conflict
+pub(crate) const FORCE_HINT: &str = "use --force to override target (add --backup to keep a copy)";
+pub(crate) const RECURSIVE_DELETE_HINT: &str = "use --allow-recursive-delete to delete the directory (or --backup to keep it)";
+pub(crate) const DIRECTORY_HINT: &str = "move or remove the target directory";
+pub(crate) const MODIFIED_DECRYPTED_HINT: &str = "copy the changes into the encrypted source or use --force to delete the decrypted file anyway";
+pub(crate) const CHANGED_TARGET_HINT: &str = "the target was changed by another program, run rstow again";
+
+/// Convert an IO error raised on `path`, to use with `map_err`
+pub(crate) fn io_error(path: &Path) -> impl FnOnce(io::Error) -> AppError + '_ {
+    move |error| AppError::IOError { path: ErrorPath::from(path), cause: error.to_string() }
 }
 
 
+/// Path displayable in error messages
 #[derive(Debug, Clone)]
 pub struct ErrorPath { path: PathBuf }
 
@@ -55,7 +262,7 @@ impl From<PathBuf> for ErrorPath {
     }
 }
 
-impl<'a> From<&'a Path> for ErrorPath {
+impl From<&Path> for ErrorPath {
     fn from(path: &Path) -> Self {
         ErrorPath { path: path.to_path_buf() }
     }
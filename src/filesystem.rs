@@ -0,0 +1,743 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::io::{Read, Write};
+use std::os::unix::fs::{lchown, symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Type of a filesystem entry
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// Filesystem operations used by rstow to plan and apply operations.
+/// Use `RealFileSystem` to work on disk or `MemoryFileSystem` to stage changes virtually.
+pub trait FileSystem: Debug + Send + Sync {
+    /// Type of a path without following a final symbolic link
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileType>;
+
+    /// Type of a path following symbolic links
+    fn metadata(&self, path: &Path) -> io::Result<FileType>;
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Absolute path with all symbolic links resolved
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Paths of the directory children (path joined with each child name)
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// First `length` bytes of a file (or less if it is shorter)
+    fn read_prefix(&self, path: &Path, length: usize) -> io::Result<Vec<u8>> {
+        let mut content = self.read(path)?;
+        content.truncate(length);
+        Ok(content)
+    }
+
+    /// Create a `target` symbolic link to `source`
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory, failing if it has children
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Write `content` to a new file created with `mode` permission bits. Fails with `AlreadyExists`
+    /// if the path exists, even as a symbolic link, which is never followed
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()>;
+
+    /// Overwrite every byte of an existing file with zeros in place (same inode and length)
+    /// and flush it to disk
+    fn zero_fill(&self, path: &Path) -> io::Result<()>;
+
+    /// Permission bits (like `0o644`) of a path following symbolic links
+    fn mode(&self, path: &Path) -> io::Result<u32>;
+
+    /// Change permission bits of a path following symbolic links
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Last modification time of a path following symbolic links
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// User and group ids owning a path following symbolic links
+    fn owner(&self, path: &Path) -> io::Result<(u32, u32)>;
+
+    /// Change user and/or group owning a path, without following a final symbolic link
+    fn set_owner(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).map(|t| t == FileType::Dir).unwrap_or(false)
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.symlink_metadata(path).map(|t| t == FileType::Symlink).unwrap_or(false)
+    }
+
+    /// Both paths resolve to the same file
+    fn is_same_file(&self, a: &Path, b: &Path) -> bool {
+        match (self.canonicalize(a), self.canonicalize(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Disk filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFileSystem;
+
+fn file_type(file_type: fs::FileType) -> FileType {
+    if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_dir() {
+        FileType::Dir
+    } else {
+        FileType::File
+    }
+}
+
+impl FileSystem for RealFileSystem {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileType> {
+        Ok(file_type(fs::symlink_metadata(path)?.file_type()))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileType> {
+        Ok(file_type(fs::metadata(path)?.file_type()))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_prefix(&self, path: &Path, length: usize) -> io::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(length);
+        fs::File::open(path)?.take(length as u64).read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        symlink(source, target)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).custom_flags(libc::O_NOFOLLOW).mode(mode).open(path)?;
+        file.write_all(content)?;
+        file.sync_all()
+    }
+
+    fn zero_fill(&self, path: &Path) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().write(true).open(path)?;
+        let zeros = [0u8; 4096];
+        let mut remaining = file.metadata()?.len();
+        while remaining > 0 {
+            let length = remaining.min(zeros.len() as u64);
+            file.write_all(&zeros[..length as usize])?;
+            remaining -= length;
+        }
+        file.sync_all()
+    }
+
+    fn mode(&self, path: &Path) -> io::Result<u32> {
+        Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn owner(&self, path: &Path) -> io::Result<(u32, u32)> {
+        let metadata = fs::metadata(path)?;
+        Ok((metadata.uid(), metadata.gid()))
+    }
+
+    fn set_owner(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        lchown(path, uid, gid)
+    }
+
+    // same inode, hard links included
+    fn is_same_file(&self, a: &Path, b: &Path) -> bool {
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+// maximum number of symbolic links followed when resolving a path
+const MAX_SYMLINKS: usize = 40;
+
+// permission bits of memory files and directories without explicit mode
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const DEFAULT_DIR_MODE: u32 = 0o755;
+// user and group ids owning memory paths without explicit owner
+const DEFAULT_OWNER: (u32, u32) = (1000, 1000);
+
+/// In-memory filesystem working on absolute paths, used to test planning and applying
+/// without touching the disk.
+#[derive(Debug, Default)]
+pub struct MemoryFileSystem {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+    // permission bits changed with `set_mode`
+    modes: Mutex<BTreeMap<PathBuf, u32>>,
+    // user and group ids changed with `set_owner`
+    owners: Mutex<BTreeMap<PathBuf, (u32, u32)>>,
+    // modification times of files, set when written
+    times: Mutex<BTreeMap<PathBuf, SystemTime>>,
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Add a file (and its parent directories) with the given content
+    pub fn add_file<P: AsRef<Path>>(&self, path: P, content: &[u8]) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let parent = self.resolve_parent(path)?;
+        self.times.lock().unwrap().insert(parent.to_path_buf(), SystemTime::now());
+        self.nodes.lock().unwrap().insert(parent, Node::File(content.to_vec()));
+        Ok(())
+    }
+
+    /// Change the modification time of an existing path
+    pub fn set_modified<P: AsRef<Path>>(&self, path: P, time: SystemTime) -> io::Result<()> {
+        let resolved = self.resolve(path.as_ref(), true)?;
+        if self.node(resolved.as_path()).is_none() {
+            return Err(not_found(path.as_ref()));
+        }
+        self.times.lock().unwrap().insert(resolved, time);
+        Ok(())
+    }
+
+    /// Add a directory and its parents
+    pub fn add_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.create_dir_all(path.as_ref())
+    }
+
+    fn node(&self, path: &Path) -> Option<Node> {
+        if path == Path::new("/") {
+            return Some(Node::Dir);
+        }
+        self.nodes.lock().unwrap().get(path).cloned()
+    }
+
+    /// Resolve symbolic links of a path. The last component is followed only if `follow` is set.
+    fn resolve(&self, path: &Path, follow: bool) -> io::Result<PathBuf> {
+        let mut followed = 0;
+        self.resolve_with(path, follow, &mut followed)
+    }
+
+    fn resolve_with(&self, path: &Path, follow: bool, followed: &mut usize) -> io::Result<PathBuf> {
+        if !path.is_absolute() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not absolute", path.display())));
+        }
+
+        let components: Vec<Component> = path.components().collect();
+        let mut resolved = PathBuf::from("/");
+        for (index, component) in components.iter().enumerate() {
+            let last = index == components.len() - 1;
+            match component {
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+                Component::ParentDir => { resolved.pop(); },
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    if let Some(Node::Symlink(link)) = self.node(resolved.as_path()) {
+                        if !last || follow {
+                            *followed += 1;
+                            if *followed > MAX_SYMLINKS {
+                                return Err(Error::other("Too many levels of symbolic links"));
+                            }
+                            resolved.pop();
+                            let link_path = resolved.join(link);
+                            resolved = self.resolve_with(link_path.as_path(), true, followed)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn resolve_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        self.resolve(path, false)
+    }
+
+    fn children(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Vec<PathBuf> {
+        nodes.range(path.to_path_buf()..)
+            .skip_while(|(p, _)| p.as_path() == path)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(p, _)| p.to_path_buf())
+            .collect()
+    }
+
+    /// Move explicit modes, owners and times of `from` and its children to `to`, or forget them
+    fn move_modes(&self, from: &Path, to: Option<&Path>) {
+        fn move_entries<T>(entries: &mut BTreeMap<PathBuf, T>, from: &Path, to: Option<&Path>) {
+            let moved: Vec<PathBuf> = entries.range(from.to_path_buf()..)
+                .take_while(|(p, _)| p.starts_with(from))
+                .map(|(p, _)| p.to_path_buf())
+                .collect();
+            for path in moved {
+                if let (Some(entry), Some(to)) = (entries.remove(&path), to) {
+                    entries.insert(to.join(path.strip_prefix(from).unwrap()), entry);
+                }
+            }
+        }
+        move_entries(&mut self.modes.lock().unwrap(), from, to);
+        move_entries(&mut self.owners.lock().unwrap(), from, to);
+        move_entries(&mut self.times.lock().unwrap(), from, to);
+    }
+
+    fn check_parent_dir(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if self.node(parent) == Some(Node::Dir) => Ok(()),
+            _ => Err(not_found(path)),
+        }
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileType> {
+        let resolved = self.resolve(path, false)?;
+        match self.node(resolved.as_path()) {
+            Some(Node::File(_)) => Ok(FileType::File),
+            Some(Node::Dir) => Ok(FileType::Dir),
+            Some(Node::Symlink(_)) => Ok(FileType::Symlink),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileType> {
+        let resolved = self.resolve(path, true)?;
+        self.symlink_metadata(resolved.as_path())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = self.resolve(path, false)?;
+        match self.node(resolved.as_path()) {
+            Some(Node::Symlink(link)) => Ok(link),
+            Some(_) => Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a symbolic link", path.display()))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = self.resolve(path, true)?;
+        self.node(resolved.as_path()).map(|_| resolved).ok_or_else(|| not_found(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let resolved = self.resolve(path, true)?;
+        if self.node(resolved.as_path()) != Some(Node::Dir) {
+            return Err(not_found(path));
+        }
+        let nodes = self.nodes.lock().unwrap();
+        Ok(MemoryFileSystem::children(&nodes, resolved.as_path()).iter()
+            .filter(|child| child.parent() == Some(resolved.as_path()))
+            .filter_map(|child| child.file_name().map(|name| path.join(name)))
+            .collect())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let resolved = self.resolve(path, true)?;
+        match self.node(resolved.as_path()) {
+            Some(Node::File(content)) => Ok(content),
+            Some(_) => Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a file", path.display()))),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        let target = self.resolve_parent(target)?;
+        self.check_parent_dir(target.as_path())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&target) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", target.display())));
+        }
+        nodes.insert(target, Node::Symlink(source.to_path_buf()));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = self.resolve_parent(from)?;
+        let to = self.resolve_parent(to)?;
+        self.check_parent_dir(to.as_path())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.remove(&from).ok_or_else(|| not_found(from.as_path()))?;
+
+        if !MemoryFileSystem::children(&nodes, to.as_path()).is_empty() {
+            nodes.insert(from, node);
+            return Err(Error::other(format!("{} is not empty", to.display())));
+        }
+
+        for child in MemoryFileSystem::children(&nodes, from.as_path()) {
+            if let Some(child_node) = nodes.remove(&child) {
+                let relative = child.strip_prefix(from.as_path()).unwrap_or(child.as_path()).to_path_buf();
+                nodes.insert(to.join(relative), child_node);
+            }
+        }
+        nodes.insert(to.to_path_buf(), node);
+        drop(nodes);
+        self.move_modes(from.as_path(), Some(to.as_path()));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = self.resolve_parent(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Dir) => Err(Error::other(format!("{} is a directory", path.display()))),
+            Some(_) => {
+                nodes.remove(&path);
+                drop(nodes);
+                self.move_modes(path.as_path(), None);
+                Ok(())
+            },
+            None => Err(not_found(path.as_path())),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let path = self.resolve_parent(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Dir) if MemoryFileSystem::children(&nodes, path.as_path()).is_empty() => {
+                nodes.remove(&path);
+                drop(nodes);
+                self.move_modes(path.as_path(), None);
+                Ok(())
+            },
+            Some(Node::Dir) => Err(Error::other(format!("{} is not empty", path.display()))),
+            Some(_) => Err(Error::other(format!("{} is not a directory", path.display()))),
+            None => Err(not_found(path.as_path())),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = self.resolve_parent(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.remove(&path).is_none() {
+            return Err(not_found(path.as_path()));
+        }
+        for child in MemoryFileSystem::children(&nodes, path.as_path()) {
+            nodes.remove(&child);
+        }
+        drop(nodes);
+        self.move_modes(path.as_path(), None);
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let path = self.resolve_parent(path)?;
+        self.check_parent_dir(path.as_path())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&path) || path == Path::new("/") {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+        }
+        nodes.insert(path, Node::Dir);
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        let resolved = self.resolve_parent(path)?;
+        self.check_parent_dir(resolved.as_path())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&resolved) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+        }
+        self.modes.lock().unwrap().insert(resolved.to_path_buf(), mode & 0o7777);
+        self.times.lock().unwrap().insert(resolved.to_path_buf(), SystemTime::now());
+        nodes.insert(resolved, Node::File(content.to_vec()));
+        Ok(())
+    }
+
+    fn zero_fill(&self, path: &Path) -> io::Result<()> {
+        let resolved = self.resolve(path, true)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get_mut(&resolved) {
+            Some(Node::File(content)) => content.iter_mut().for_each(|byte| *byte = 0),
+            Some(_) => return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a file", path.display()))),
+            None => return Err(not_found(path)),
+        }
+        self.times.lock().unwrap().insert(resolved, SystemTime::now());
+        Ok(())
+    }
+
+    fn mode(&self, path: &Path) -> io::Result<u32> {
+        let resolved = self.resolve(path, true)?;
+        let default = match self.node(resolved.as_path()) {
+            Some(Node::Dir) => DEFAULT_DIR_MODE,
+            Some(_) => DEFAULT_FILE_MODE,
+            None => return Err(not_found(path)),
+        };
+        Ok(self.modes.lock().unwrap().get(&resolved).cloned().unwrap_or(default))
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let resolved = self.resolve(path, true)?;
+        if self.node(resolved.as_path()).is_none() {
+            return Err(not_found(path));
+        }
+        self.modes.lock().unwrap().insert(resolved, mode & 0o7777);
+        Ok(())
+    }
+
+    // directories and links have no time
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        let resolved = self.resolve(path, true)?;
+        if self.node(resolved.as_path()).is_none() {
+            return Err(not_found(path));
+        }
+        Ok(self.times.lock().unwrap().get(&resolved).cloned().unwrap_or(SystemTime::UNIX_EPOCH))
+    }
+
+    fn owner(&self, path: &Path) -> io::Result<(u32, u32)> {
+        let resolved = self.resolve(path, true)?;
+        if self.node(resolved.as_path()).is_none() {
+            return Err(not_found(path));
+        }
+        Ok(self.owners.lock().unwrap().get(&resolved).cloned().unwrap_or(DEFAULT_OWNER))
+    }
+
+    fn set_owner(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        let resolved = self.resolve_parent(path)?;
+        if self.node(resolved.as_path()).is_none() {
+            return Err(not_found(path));
+        }
+        let mut owners = self.owners.lock().unwrap();
+        let (current_uid, current_gid) = owners.get(&resolved).cloned().unwrap_or(DEFAULT_OWNER);
+        owners.insert(resolved, (uid.unwrap_or(current_uid), gid.unwrap_or(current_gid)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_filesystem {
+    use super::*;
+    use test_utils::*;
+
+    #[test]
+    fn test_memory_files() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/dir/file.txt", b"content").unwrap();
+
+        assert!(fs.is_dir(Path::new("/source/dir")));
+        assert_eq!(fs.metadata(Path::new("/source/dir/file.txt")).unwrap(), FileType::File);
+        assert_eq!(fs.read(Path::new("/source/dir/file.txt")).unwrap(), b"content".to_vec());
+        assert_eq!(fs.read_dir(Path::new("/source")).unwrap(), vec![PathBuf::from("/source/dir")]);
+        assert!(!fs.exists(Path::new("/source/other")));
+    }
+
+    #[test]
+    fn test_memory_symlinks() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/dir/file.txt", b"").unwrap();
+        fs.add_dir("/target").unwrap();
+        fs.symlink(Path::new("/source/dir"), Path::new("/target/dir")).unwrap();
+        fs.symlink(Path::new("/source/missing"), Path::new("/target/dangling")).unwrap();
+
+        assert!(fs.is_symlink(Path::new("/target/dir")));
+        assert!(fs.is_dir(Path::new("/target/dir")));
+        assert_eq!(fs.canonicalize(Path::new("/target/dir/file.txt")).unwrap(), PathBuf::from("/source/dir/file.txt"));
+        assert_eq!(fs.read_dir(Path::new("/target/dir")).unwrap(), vec![PathBuf::from("/target/dir/file.txt")]);
+
+        assert!(fs.is_symlink(Path::new("/target/dangling")));
+        assert!(!fs.exists(Path::new("/target/dangling")));
+        assert!(fs.symlink(Path::new("/source/dir"), Path::new("/target/dir")).is_err());
+    }
+
+    #[test]
+    fn test_memory_rename_and_remove() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/target/dir/file.txt", b"").unwrap();
+
+        fs.rename(Path::new("/target/dir"), Path::new("/target/dir.backup")).unwrap();
+        assert!(!fs.exists(Path::new("/target/dir")));
+        assert!(fs.exists(Path::new("/target/dir.backup/file.txt")));
+
+        assert!(fs.remove_file(Path::new("/target/dir.backup")).is_err());
+        fs.remove_dir_all(Path::new("/target/dir.backup")).unwrap();
+        assert!(!fs.exists(Path::new("/target/dir.backup/file.txt")));
+        assert!(fs.read_dir(Path::new("/target")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_modes() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/.ssh/config", b"").unwrap();
+        fs.symlink(Path::new("/source/.ssh"), Path::new("/ssh")).unwrap();
+        assert_eq!(fs.mode(Path::new("/source/.ssh")).unwrap(), 0o755);
+        assert_eq!(fs.mode(Path::new("/ssh/config")).unwrap(), 0o644);
+
+        fs.set_mode(Path::new("/ssh/config"), 0o600).unwrap();
+        assert_eq!(fs.mode(Path::new("/source/.ssh/config")).unwrap(), 0o600);
+
+        fs.rename(Path::new("/source/.ssh"), Path::new("/source/ssh")).unwrap();
+        assert_eq!(fs.mode(Path::new("/source/ssh/config")).unwrap(), 0o600);
+        fs.remove_dir_all(Path::new("/source/ssh")).unwrap();
+        fs.add_file("/source/ssh/config", b"").unwrap();
+        assert_eq!(fs.mode(Path::new("/source/ssh/config")).unwrap(), 0o644);
+        assert!(fs.set_mode(Path::new("/missing"), 0o600).is_err());
+    }
+
+    #[test]
+    fn test_memory_owners() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/etc/app").unwrap();
+        assert_eq!(fs.owner(Path::new("/etc/app")).unwrap(), (1000, 1000));
+
+        fs.set_owner(Path::new("/etc/app"), Some(0), None).unwrap();
+        assert_eq!(fs.owner(Path::new("/etc/app")).unwrap(), (0, 1000));
+        fs.set_owner(Path::new("/etc/app"), None, Some(0)).unwrap();
+        assert_eq!(fs.owner(Path::new("/etc/app")).unwrap(), (0, 0));
+
+        fs.remove_dir_all(Path::new("/etc/app")).unwrap();
+        fs.add_dir("/etc/app").unwrap();
+        assert_eq!(fs.owner(Path::new("/etc/app")).unwrap(), (1000, 1000));
+    }
+
+    #[test]
+    fn test_zero_fill() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/target/secret", b"key").unwrap();
+        fs.zero_fill(Path::new("/target/secret")).unwrap();
+        assert_eq!(fs.read(Path::new("/target/secret")).unwrap(), vec![0u8; 3]);
+
+        with_test_directories("test_zero_fill", |_, target| {
+            let path = target.join("secret");
+            fs::write(path.as_path(), vec![b'k'; 5000]).unwrap();
+            let before = fs::metadata(path.as_path()).unwrap();
+
+            RealFileSystem.zero_fill(path.as_path()).unwrap();
+
+            // the file is overwritten in place, not truncated or replaced
+            let after = fs::metadata(path.as_path()).unwrap();
+            assert_eq!(after.ino(), before.ino());
+            assert_eq!(after.len(), 5000);
+            assert_eq!(fs::read(path.as_path()).unwrap(), vec![0u8; 5000]);
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_write_new_file() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/target/existing", b"existing").unwrap();
+        fs.symlink(Path::new("/target/existing"), Path::new("/target/link")).unwrap();
+        fs.write(Path::new("/target/secret"), b"key", 0o600).unwrap();
+        assert_eq!(fs.read(Path::new("/target/secret")).unwrap(), b"key".to_vec());
+        assert_eq!(fs.mode(Path::new("/target/secret")).unwrap(), 0o600);
+        assert_eq!(fs.write(Path::new("/target/link"), b"key", 0o600).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs.read(Path::new("/target/existing")).unwrap(), b"existing".to_vec());
+
+        with_test_directories("test_write_new_file", |source, target| {
+            let existing = source.join("existing");
+            let link = target.join("link");
+            fs::write(existing.as_path(), b"existing").unwrap();
+            symlink(existing.as_path(), link.as_path()).unwrap();
+
+            // the link is not followed
+            assert_eq!(RealFileSystem.write(link.as_path(), b"key", 0o600).unwrap_err().kind(), ErrorKind::AlreadyExists);
+            assert_eq!(fs::read(existing.as_path()).unwrap(), b"existing".to_vec());
+
+            let secret = target.join("secret");
+            RealFileSystem.write(secret.as_path(), b"key", 0o600).unwrap();
+            assert_eq!(fs::metadata(secret.as_path()).unwrap().permissions().mode() & 0o7777, 0o600);
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_read_prefix() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/file", b"content").unwrap();
+        assert_eq!(fs.read_prefix(Path::new("/source/file"), 4).unwrap(), b"cont".to_vec());
+        assert_eq!(fs.read_prefix(Path::new("/source/file"), 4096).unwrap(), b"content".to_vec());
+
+        with_test_directories("test_read_prefix", |source, _| {
+            let path = source.join("large");
+            fs::write(path.as_path(), vec![b'a'; 10000]).unwrap();
+            assert_eq!(RealFileSystem.read_prefix(path.as_path(), 4096).unwrap(), vec![b'a'; 4096]);
+        }).unwrap();
+    }
+}
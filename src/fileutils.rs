@@ -3,89 +3,187 @@ use quicli::prelude::*;
 
 use std::io;
 use std::io::{Error, ErrorKind};
-use std::fs::{self};
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
-use std::os::unix::fs::symlink;
+use std::result::Result;
 
-pub(crate) fn create_symlink(source_path: &Path, target_path: &Path) -> io::Result<()> {
+use crypto;
+use errors::{io_error, AppError, ErrorPath, CHANGED_TARGET_HINT};
+use filesystem::{FileSystem, FileType};
+
+pub(crate) fn create_symlink(fs: &dyn FileSystem, source_path: &Path, target_path: &Path) -> io::Result<()> {
     if cfg!(target_family = "unix") {
         info!("create symbolic link {} -> {}", source_path.display(), target_path.display());
-        symlink(source_path, target_path)
+        fs.symlink(source_path, target_path)
     } else {
-        Err(Error::new(ErrorKind::Other, "OS not supported"))
+        Err(Error::other("OS not supported"))
     }
 }
 
-pub(crate) fn build_backup_path(path: &Path) ->io::Result<PathBuf> {
-    let file_name = path.file_name()
-        .and_then(|x: &OsStr| x.to_str())
-        .expect("Unable to get filename");
+/// Replace `target_path` by a symbolic link to `source_path` without removing it first : the new
+/// link is created next to it then renamed over it
+pub(crate) fn replace_symlink(fs: &dyn FileSystem, source_path: &Path, target_path: &Path) -> Result<(), AppError> {
+    let temporary_path = build_temporary_path(target_path)?;
+    info!("replace symbolic link {} -> {}", source_path.display(), target_path.display());
+    if fs.is_symlink(temporary_path.as_path()) {
+        // left by an interrupted run
+        fs.remove_file(temporary_path.as_path()).map_err(io_error(temporary_path.as_path()))?;
+    }
+    create_symlink(fs, source_path, temporary_path.as_path()).map_err(io_error(temporary_path.as_path()))?;
+    if let Err(e) = fs.rename(temporary_path.as_path(), target_path) {
+        fs.remove_file(temporary_path.as_path()).ok();
+        return Err(io_error(target_path)(e));
+    }
+    Ok(())
+}
+
+pub(crate) fn build_temporary_path(path: &Path) -> Result<PathBuf, AppError> {
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(file_name(path)?);
+    temporary_name.push(".rstow-tmp");
+    Ok(path.with_file_name(temporary_name))
+}
 
-    let backup_name = format!("{}.backup", file_name);
+pub(crate) fn build_backup_path(path: &Path) -> Result<PathBuf, AppError> {
+    let mut backup_name = file_name(path)?.to_os_string();
+    backup_name.push(".backup");
 
-    let parent_path = path.parent().expect("Unable to get parent directory");
-    Ok(parent_path.join(backup_name.to_owned()))
+    let parent_path = path.parent().ok_or_else(|| AppError::InvalidPath {
+        path: ErrorPath::from(path),
+        cause: "Unable to get parent directory".to_string()
+    })?;
+    Ok(parent_path.join(backup_name))
 }
 
-pub(crate) fn backup_path(path: &Path) -> io::Result<()> {
+pub(crate) fn backup_path(fs: &dyn FileSystem, path: &Path) -> Result<(), AppError> {
     let backup_path = build_backup_path(path)?;
 
     info!("backup {} into {}", path.display(), backup_path.as_path().display());
-    fs::rename(path, backup_path.as_path())
+    fs.rename(path, backup_path.as_path()).map_err(io_error(path))?;
+    Ok(())
+}
+
+/// `path` relative to its `base` ancestor
+pub(crate) fn relative_path<'p>(path: &'p Path, base: &Path) -> Result<&'p Path, AppError> {
+    path.strip_prefix(base).map_err(|_| AppError::InvalidPath {
+        path: ErrorPath::from(path),
+        cause: format!("Path is not in {}", base.display())
+    })
 }
 
-pub(crate) fn restore_path(backup: &Path, target: &Path) -> io::Result<()> {
+pub(crate) fn file_name(path: &Path) -> Result<&OsStr, AppError> {
+    path.file_name().ok_or_else(|| AppError::InvalidPath {
+        path: ErrorPath::from(path),
+        cause: "Unable to get path filename".to_string()
+    })
+}
+
+/// File name of a path as an UTF-8 string
+pub(crate) fn file_name_str(path: &Path) -> Result<&str, AppError> {
+    file_name(path)?.to_str().ok_or_else(|| AppError::NonUtf8Name { path: ErrorPath::from(path) })
+}
+
+pub(crate) fn restore_path(fs: &dyn FileSystem, backup: &Path, target: &Path) -> io::Result<()> {
     info!("restore backup {} into {}", backup.display(), target.display());
-    fs::rename(backup, target)
+    fs.rename(backup, target)
 }
 
-pub(crate) fn delete_path(path: &Path) -> io::Result<()> {
-    if path.is_dir() {
-        info!("delete directory recursively {}", path.display());
-        fs::remove_dir_all(path)
-    } else {
-        info!("delete file {}", path.display());
-        fs::remove_file(path)
+/// Delete a path planned as a `DeleteSymlink`, `DeleteFile`, `DeleteEmptyDir` or `DeleteDirRecursive`
+/// operation, after checking it still has the planned type
+pub(crate) fn delete_path(fs: &dyn FileSystem, path: &Path, expected: FileType, recursive: bool) -> Result<(), AppError> {
+    let changed = |cause: String| AppError::PathChanged { path: ErrorPath::from(path), cause };
+    let actual = fs.symlink_metadata(path).map_err(|e| changed(format!("not deleted ({})", e)))?;
+    if actual != expected {
+        return Err(changed(format!("planned to delete a {} but found a {}", type_name(expected), type_name(actual))));
+    }
+    match expected {
+        FileType::Dir if recursive => {
+            info!("delete directory recursively {}", path.display());
+            fs.remove_dir_all(path).map_err(io_error(path))?;
+        },
+        FileType::Dir => {
+            info!("delete empty directory {}", path.display());
+            fs.remove_dir(path).map_err(|e| changed(format!("directory not deleted ({})", e)))?;
+        },
+        _ => {
+            info!("delete {} {}", type_name(expected), path.display());
+            fs.remove_file(path).map_err(io_error(path))?;
+        },
     }
+    Ok(())
 }
 
-pub(crate) fn is_symlink(path: &Path) -> bool {
-    match path.symlink_metadata() {
-        Ok(data) => data.file_type().is_symlink(),
-        Err(_e) => false
+fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::File => "file",
+        FileType::Dir => "directory",
+        FileType::Symlink => "symbolic link",
     }
 }
 
-pub(crate) fn check_symlink(symlink_path: &Path, valid_dest: &Path) -> bool {
-    match get_symlink_target(symlink_path) {
+pub(crate) fn is_symlink(fs: &dyn FileSystem, path: &Path) -> bool {
+    fs.is_symlink(path)
+}
+
+pub(crate) fn check_symlink(fs: &dyn FileSystem, symlink_path: &Path, valid_dest: &Path) -> bool {
+    match get_symlink_target(fs, symlink_path) {
         Some(target) => valid_dest.eq(target.as_path()),
         None => false
     }
 }
 
-pub(crate) fn get_symlink_target(symlink_path: &Path) -> Option<PathBuf> {
-    if is_symlink(symlink_path) {
-        let error = format!("Unable to find absolute path of symlink target {}", symlink_path.display());
-        let absolute_target = symlink_path.canonicalize().expect(error.as_str());
-        Some(absolute_target.to_owned())
+/// Absolute path of a symbolic link target, `None` if the path is not a symbolic link
+/// or if its target doesn't exist
+pub(crate) fn get_symlink_target(fs: &dyn FileSystem, symlink_path: &Path) -> Option<PathBuf> {
+    if is_symlink(fs, symlink_path) {
+        match fs.canonicalize(symlink_path) {
+            Ok(absolute_target) => Some(absolute_target),
+            Err(e) => {
+                debug!("Unable to find absolute path of symlink target {} : {}", symlink_path.display(), e);
+                None
+            }
+        }
     } else {
         None
     }
 }
 
-pub(crate) fn break_directory_link(directory: &Path) -> io::Result<()> {
-    let target = get_symlink_target(directory).unwrap();
+pub(crate) fn decrypt_file(fs: &dyn FileSystem, source: &Path, target: &Path, identity: &Path) -> Result<(), AppError> {
+    info!("decrypt {} into {}", source.display(), target.display());
+    let decrypted = crypto::decrypt(fs, identity, source)?;
+    fs.write(target, &decrypted, crypto::DECRYPTED_MODE).map_err(|e| match e.kind() {
+        // planned on a missing path, something was created since
+        ErrorKind::AlreadyExists => AppError::StowPathError {
+            source: ErrorPath::from(source),
+            target: ErrorPath::from(target),
+            cause: "Target file was created after planning".to_string(),
+            hint: CHANGED_TARGET_HINT,
+        },
+        _ => io_error(target)(e),
+    })
+}
+
+/// Overwrite a regular file with zeros in place before deleting it
+pub(crate) fn secure_delete(fs: &dyn FileSystem, path: &Path) -> io::Result<()> {
+    info!("securely delete {}", path.display());
+    if fs.symlink_metadata(path)? != FileType::File {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a regular file", path.display())));
+    }
+    fs.zero_fill(path)?;
+    fs.remove_file(path)
+}
+
+pub(crate) fn break_directory_link(fs: &dyn FileSystem, directory: &Path) -> Result<(), AppError> {
+    let target = get_symlink_target(fs, directory).ok_or_else(|| AppError::MissingTarget { path: ErrorPath::from(directory) })?;
 
     // replace symlink with real directory file
-    delete_path(directory)?;
-    create_dir(directory)?;
-
-    let source_paths = fs::read_dir(target.as_path())?;
-    for src_dir_entry in source_paths {
-        let source_child = src_dir_entry?.path();
-        let target_child = target.join(source_child.as_path().file_name().expect("Unable to get path filename"));
-        create_symlink(source_child.as_path(), target_child.as_path())?;
+    delete_path(fs, directory, FileType::Symlink, false)?;
+    fs.create_dir(directory).map_err(io_error(directory))?;
+
+    let source_paths = fs.read_dir(target.as_path()).map_err(io_error(target.as_path()))?;
+    for source_child in source_paths {
+        let target_child = directory.join(file_name(source_child.as_path())?);
+        create_symlink(fs, source_child.as_path(), target_child.as_path()).map_err(io_error(target_child.as_path()))?;
     }
 
     Ok(())
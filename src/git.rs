@@ -0,0 +1,275 @@
+use quicli::prelude::*;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use errors::{io_error, AppError, ErrorPath};
+use filesystem::FileSystem;
+
+pub(crate) const GIT_DIR_NAME: &str = ".git";
+
+/// Git repository containing a source directory, read locally from its `.git` directory
+#[derive(Debug)]
+pub(crate) struct GitRepository {
+    root: PathBuf,
+    git_dir: PathBuf,
+    // files of the index, relative to `root`, when only tracked files are stowed
+    tracked: Option<BTreeSet<PathBuf>>,
+    // `.gitignore` rules of each directory read so far
+    ignores: RefCell<BTreeMap<PathBuf, Gitignore>>,
+}
+
+impl GitRepository {
+    /// Repository containing `path` if any. With `tracked_only`, files missing from the
+    /// repository index are ignored.
+    pub(crate) fn find(fs: &dyn FileSystem, path: &Path, tracked_only: bool) -> Result<Option<GitRepository>, AppError> {
+        for root in path.ancestors() {
+            if let Some(git_dir) = git_dir(fs, root)? {
+                let index = git_dir.join("index");
+                let tracked = if !tracked_only {
+                    None
+                } else if fs.exists(index.as_path()) {
+                    Some(read_index(fs, index.as_path())?)
+                } else {
+                    // nothing added yet
+                    Some(BTreeSet::new())
+                };
+                return Ok(Some(GitRepository { root: root.to_path_buf(), git_dir, tracked, ignores: RefCell::new(BTreeMap::new()) }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// `.git` directories, files not tracked (with `tracked_only`) and files matching
+    /// `.gitignore` rules are ignored
+    pub(crate) fn is_ignored(&self, fs: &dyn FileSystem, path: &Path) -> bool {
+        if path.file_name() == Some(OsStr::new(GIT_DIR_NAME)) {
+            return true;
+        }
+        let relative = match path.strip_prefix(self.root.as_path()) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+
+        match &self.tracked {
+            Some(tracked) => !tracked.range(relative.to_path_buf()..).next().is_some_and(|p| p.starts_with(relative)),
+            None => {
+                let is_dir = fs.is_dir(path);
+                // rules of the nearest .gitignore win
+                for directory in path.ancestors().skip(1).take_while(|d| d.starts_with(self.root.as_path())) {
+                    let mut ignores = self.ignores.borrow_mut();
+                    let gitignore = ignores.entry(directory.to_path_buf()).or_insert_with(|| self.read_gitignore(fs, directory));
+                    let matched = gitignore.matched(path, is_dir);
+                    if matched.is_ignore() {
+                        return true;
+                    } else if matched.is_whitelist() {
+                        return false;
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    fn read_gitignore(&self, fs: &dyn FileSystem, directory: &Path) -> Gitignore {
+        let mut files = vec![directory.join(".gitignore")];
+        if directory == self.root.as_path() {
+            files.push(self.git_dir.join("info").join("exclude"));
+        }
+
+        let mut builder = GitignoreBuilder::new(directory);
+        for file in files.iter().filter(|f| fs.exists(f)) {
+            let content = match fs.read(file) {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!("Unable to read {} : {}", file.display(), e);
+                    continue;
+                }
+            };
+            for line in String::from_utf8_lossy(&content).lines() {
+                if let Err(e) = builder.add_line(Some(file.to_path_buf()), line) {
+                    warn!("Invalid rule in {} : {}", file.display(), e);
+                }
+            }
+        }
+        builder.build().unwrap_or_else(|_| Gitignore::empty())
+    }
+}
+
+/// Git directory of a repository root : a `.git` directory or the directory named in a `.git` file
+fn git_dir(fs: &dyn FileSystem, root: &Path) -> Result<Option<PathBuf>, AppError> {
+    let dot_git = root.join(GIT_DIR_NAME);
+    if fs.is_dir(dot_git.as_path()) {
+        Ok(Some(dot_git))
+    } else if fs.exists(dot_git.as_path()) {
+        let content = String::from_utf8_lossy(&fs.read(dot_git.as_path()).map_err(io_error(dot_git.as_path()))?).to_string();
+        match content.trim().strip_prefix("gitdir:") {
+            Some(git_dir) => Ok(Some(root.join(git_dir.trim()))),
+            None => Err(AppError::GitError { path: ErrorPath::from(dot_git), cause: "Invalid .git file".to_string() }),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Paths (relative to the repository root) of the entries of a git index file (versions 2 to 4)
+fn read_index(fs: &dyn FileSystem, index: &Path) -> Result<BTreeSet<PathBuf>, AppError> {
+    let data = fs.read(index).map_err(io_error(index))?;
+    let error = |cause: &str| AppError::GitError { path: ErrorPath::from(index), cause: cause.to_string() };
+    let be_u32 = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    if data.len() < 12 || &data[0..4] != b"DIRC" {
+        return Err(error("Not a git index file"));
+    }
+    let version = be_u32(&data[4..8]);
+    if !(2..=4).contains(&version) {
+        return Err(error(&format!("Unsupported git index version {}", version)));
+    }
+    let count = be_u32(&data[8..12]) as usize;
+
+    let mut paths: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut previous: Vec<u8> = Vec::new();
+    let mut offset = 12;
+    for _ in 0..count {
+        let start = offset;
+        // times, device, inode, mode, uid, gid, size and object id
+        offset += 60;
+        let flags = data.get(offset..offset + 2).ok_or_else(|| error("Truncated index entry"))?;
+        offset += 2;
+        if version >= 3 && flags[0] & 0x40 != 0 {
+            // extended flags
+            offset += 2;
+        }
+
+        let path = if version == 4 {
+            // path prefix compression : length to remove from previous path then the suffix
+            let (strip, length) = read_varint(data.get(offset..).unwrap_or(&[])).ok_or_else(|| error("Truncated index entry"))?;
+            offset += length;
+            let end = find_nul(&data, offset).ok_or_else(|| error("Truncated index entry"))?;
+            let kept = previous.len().checked_sub(strip).ok_or_else(|| error("Invalid path compression"))?;
+            let path = [&previous[..kept], &data[offset..end]].concat();
+            offset = end + 1;
+            path
+        } else {
+            let end = find_nul(&data, offset).ok_or_else(|| error("Truncated index entry"))?;
+            let path = data[offset..end].to_vec();
+            // entries are padded with 1 to 8 NUL bytes to a multiple of 8 bytes
+            offset = start + ((end - start + 8) & !7);
+            path
+        };
+
+        paths.insert(PathBuf::from(OsStr::from_bytes(&path)));
+        previous = path;
+    }
+    Ok(paths)
+}
+
+fn find_nul(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?.iter().position(|b| *b == 0).map(|i| from + i)
+}
+
+/// Git variable length integer (offset encoding) and the number of bytes read
+fn read_varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut bytes = data.iter();
+    let mut byte = *bytes.next()?;
+    let mut value = (byte & 0x7f) as usize;
+    let mut length = 1;
+    while byte & 0x80 != 0 {
+        byte = *bytes.next()?;
+        value = ((value + 1) << 7) + (byte & 0x7f) as usize;
+        length += 1;
+    }
+    Some((value, length))
+}
+
+#[cfg(test)]
+mod test_git {
+    use super::*;
+    use filesystem::MemoryFileSystem;
+    use operations::FSOperation;
+    use stower::Stower;
+    use test_utils::memory_source;
+
+    fn index_entry(path: &str) -> Vec<u8> {
+        let mut entry = vec![0u8; 60];
+        entry.extend_from_slice(&(path.len() as u16).to_be_bytes());
+        entry.extend_from_slice(path.as_bytes());
+        let padding = 8 - entry.len() % 8;
+        entry.extend(vec![0u8; padding]);
+        entry
+    }
+
+    fn index(paths: &[&str]) -> Vec<u8> {
+        let mut index = b"DIRC".to_vec();
+        index.extend_from_slice(&2u32.to_be_bytes());
+        index.extend_from_slice(&(paths.len() as u32).to_be_bytes());
+        for path in paths {
+            index.extend(index_entry(path));
+        }
+        index
+    }
+
+    #[test]
+    fn test_read_index() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/repo/.git/index", &index(&["a.txt", "dir/some-long-name.txt"])).unwrap();
+
+        let paths = read_index(&fs, Path::new("/repo/.git/index")).unwrap();
+        assert_eq!(paths.into_iter().collect::<Vec<PathBuf>>(), vec![PathBuf::from("a.txt"), PathBuf::from("dir/some-long-name.txt")]);
+
+        fs.add_file("/repo/.git/index", b"not an index").unwrap();
+        assert!(read_index(&fs, Path::new("/repo/.git/index")).is_err());
+    }
+
+    #[test]
+    fn test_tracked_files() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/repo/.git/index", &index(&["home/dir/file.txt"])).unwrap();
+
+        let repository = GitRepository::find(&fs, Path::new("/repo/home"), true).unwrap().unwrap();
+        assert!(!repository.is_ignored(&fs, Path::new("/repo/home/dir")));
+        assert!(!repository.is_ignored(&fs, Path::new("/repo/home/dir/file.txt")));
+        assert!(repository.is_ignored(&fs, Path::new("/repo/home/dir/build.o")));
+        assert!(repository.is_ignored(&fs, Path::new("/repo/home/di")));
+        assert!(repository.is_ignored(&fs, Path::new("/repo/home/.git")));
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let fs = MemoryFileSystem::new();
+        fs.add_dir("/repo/.git").unwrap();
+        fs.add_file("/repo/.gitignore", b"*.log\n/build\n").unwrap();
+        fs.add_file("/repo/home/.gitignore", b"!keep.log\n").unwrap();
+        fs.add_dir("/repo/build").unwrap();
+
+        let repository = GitRepository::find(&fs, Path::new("/repo/home"), false).unwrap().unwrap();
+        assert!(repository.is_ignored(&fs, Path::new("/repo/home/debug.log")));
+        assert!(!repository.is_ignored(&fs, Path::new("/repo/home/keep.log")));
+        assert!(!repository.is_ignored(&fs, Path::new("/repo/home/file.txt")));
+        assert!(repository.is_ignored(&fs, Path::new("/repo/build")));
+        assert!(GitRepository::find(&fs, Path::new("/other"), false).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_nested_repository() {
+        let fs = memory_source();
+        fs.add_file("/source/pkg/.rstow", b"symlink_current_dir = false\nignore_files = []").unwrap();
+        fs.add_file("/source/pkg/.git/HEAD", b"ref: refs/heads/main").unwrap();
+        fs.add_file("/source/pkg/app.conf", b"").unwrap();
+
+        // never stowed, even without default patterns or a repository above it
+        let plan = Stower::new("/source", "/target").default_ignore(false).filesystem(fs.clone()).plan().unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/dir"), target: PathBuf::from("/target/dir") },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/file.txt"), target: PathBuf::from("/target/file.txt") },
+            FSOperation::CreateDir(PathBuf::from("/target/pkg")),
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/pkg/app.conf"), target: PathBuf::from("/target/pkg/app.conf") },
+        ]);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use errors::*;
+use operations::FSOperation;
+
+/// A planned operation (or the error found when planning it) with the indexes of the nodes
+/// it depends on. Dependencies always point to previous nodes.
+#[derive(Debug, Clone)]
+pub struct OperationNode {
+    pub operation: Result<FSOperation, AppError>,
+    pub dependencies: Vec<usize>,
+}
+
+/// Operations plan where each `FSOperation` is linked to the operations that read or write
+/// the same paths (or one of their parents) before it.
+#[derive(Debug, Clone, Default)]
+pub struct OperationGraph {
+    nodes: Vec<OperationNode>,
+    // every path touched so far with the nodes touching it (and if they write it)
+    paths: BTreeMap<PathBuf, Vec<(usize, bool)>>,
+}
+
+impl OperationGraph {
+    pub fn new() -> OperationGraph {
+        OperationGraph::default()
+    }
+
+    /// Add an operation after all previous ones and compute its dependencies
+    pub fn push(&mut self, operation: Result<FSOperation, AppError>) -> usize {
+        let index = self.nodes.len();
+        let (reads, writes) = match &operation {
+            Ok(op) => (op.requires(), [op.removes(), op.produces(), op.modifies()].concat()),
+            // the state of a path in error is unknown, following operations on it depend on the error
+            Err(err) => (Vec::new(), err.path().map(|p| p.to_path_buf()).into_iter().collect()),
+        };
+
+        let mut dependencies: BTreeSet<usize> = BTreeSet::new();
+
+        // a read or a write depends on previous writes of the same path or one of its parents
+        for path in reads.iter().chain(writes.iter()) {
+            for ancestor in path.ancestors() {
+                if let Some(nodes) = self.paths.get(ancestor) {
+                    dependencies.extend(nodes.iter().filter(|(_, write)| *write).map(|(i, _)| *i));
+                }
+            }
+        }
+
+        // a write depends on every previous access of the same path or one of its children
+        for path in writes.iter() {
+            let children = self.paths.range(path.to_path_buf()..)
+                .take_while(|(p, _)| p.starts_with(path));
+            for (_, nodes) in children {
+                dependencies.extend(nodes.iter().map(|(i, _)| *i));
+            }
+        }
+
+        for path in reads {
+            self.paths.entry(path).or_default().push((index, false));
+        }
+        for path in writes {
+            self.paths.entry(path).or_default().push((index, true));
+        }
+
+        self.nodes.push(OperationNode { operation, dependencies: dependencies.into_iter().collect() });
+        index
+    }
+
+    pub fn nodes(&self) -> &[OperationNode] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Operations in application order
+    pub fn operations(&self) -> impl Iterator<Item = &Result<FSOperation, AppError>> {
+        self.nodes.iter().map(|node| &node.operation)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &AppError> {
+        self.operations().filter_map(|res| res.as_ref().err())
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// All nodes depending directly or transitively on the node at `index`
+    pub fn dependents(&self, index: usize) -> BTreeSet<usize> {
+        let mut dependents: BTreeSet<usize> = BTreeSet::new();
+        for (i, node) in self.nodes.iter().enumerate().skip(index + 1) {
+            if node.dependencies.iter().any(|d| *d == index || dependents.contains(d)) {
+                dependents.insert(i);
+            }
+        }
+        dependents
+    }
+
+    /// Split nodes into groups of operations with no dependency between groups.
+    /// Each group keeps plan order and groups are sorted by their first node.
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        let mut roots: Vec<usize> = (0..self.nodes.len()).collect();
+
+        fn find(roots: &mut [usize], index: usize) -> usize {
+            let mut root = index;
+            while roots[root] != root {
+                root = roots[root];
+            }
+            roots[index] = root;
+            root
+        }
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            for dependency in node.dependencies.iter() {
+                let a = find(&mut roots, index);
+                let b = find(&mut roots, *dependency);
+                roots[a.max(b)] = a.min(b);
+            }
+        }
+
+        let mut components: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for index in 0..self.nodes.len() {
+            let root = find(&mut roots, index);
+            components.entry(root).or_default().push(index);
+        }
+        components.into_values().collect()
+    }
+
+    /// Check plan consistency :
+    /// - a path can't be created twice without being removed in between
+    /// - a parent directory required by an operation must not be created by a following operation
+    pub fn validate(&self) -> Vec<AppError> {
+        let mut errors: Vec<AppError> = Vec::new();
+        let mut created: BTreeMap<PathBuf, usize> = BTreeMap::new();
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            if let Ok(op) = &node.operation {
+                let produces = op.produces();
+                let parents = op.requires().into_iter()
+                    .filter(|r| produces.iter().any(|p| p != r && p.starts_with(r)));
+                for required in parents {
+                    if !created.contains_key(&required) && self.produced_after(index, required.as_path()) {
+                        errors.push(AppError::InvalidPlan {
+                            path: ErrorPath::from(required.as_path()),
+                            cause: "Path required before being created".to_string()
+                        });
+                    }
+                }
+                for removed in op.removes() {
+                    created.remove(&removed);
+                }
+                for produced in produces {
+                    if created.contains_key(&produced) {
+                        errors.push(AppError::InvalidPlan {
+                            path: ErrorPath::from(produced.as_path()),
+                            cause: "Path created by more than one operation".to_string()
+                        });
+                    }
+                    created.insert(produced, index);
+                }
+            }
+        }
+        errors
+    }
+
+    fn produced_after(&self, index: usize, path: &Path) -> bool {
+        self.nodes.iter().skip(index + 1).any(|node| {
+            match &node.operation {
+                Ok(op) => op.produces().iter().any(|p| p == path),
+                Err(_) => false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_graph {
+    use super::*;
+
+    fn symlink(source: &str, target: &str) -> Result<FSOperation, AppError> {
+        Ok(FSOperation::CreateSymlink { source: PathBuf::from(source), target: PathBuf::from(target) })
+    }
+
+    #[test]
+    fn test_independent_operations() {
+        let mut graph = OperationGraph::new();
+        graph.push(symlink("/source/a", "/target/a"));
+        graph.push(symlink("/source/b", "/target/b"));
+
+        assert_eq!(graph.len(), 2);
+        assert!(graph.nodes()[0].dependencies.is_empty());
+        assert!(graph.nodes()[1].dependencies.is_empty());
+        assert!(graph.validate().is_empty());
+    }
+
+    #[test]
+    fn test_same_path_dependency() {
+        let mut graph = OperationGraph::new();
+        graph.push(Ok(FSOperation::DeleteFile(PathBuf::from("/target/a"))));
+        graph.push(symlink("/source/a", "/target/a"));
+
+        assert_eq!(graph.nodes()[1].dependencies, vec![0]);
+        assert!(graph.validate().is_empty());
+    }
+
+    #[test]
+    fn test_parent_dependency() {
+        let mut graph = OperationGraph::new();
+        graph.push(Ok(FSOperation::CreateDir(PathBuf::from("/target/dir"))));
+        graph.push(symlink("/source/dir/a", "/target/dir/a"));
+        graph.push(symlink("/source/b", "/target/b"));
+
+        assert_eq!(graph.nodes()[1].dependencies, vec![0]);
+        assert!(graph.nodes()[2].dependencies.is_empty());
+        assert_eq!(graph.dependents(0).into_iter().collect::<Vec<usize>>(), vec![1]);
+        assert!(graph.validate().is_empty());
+    }
+
+    #[test]
+    fn test_error_dependency() {
+        let mut graph = OperationGraph::new();
+        graph.push(Err(AppError::StowPathError {
+            source: ErrorPath::from(PathBuf::from("/source/dir")),
+            target: ErrorPath::from(PathBuf::from("/target/dir")),
+            cause: "conflict".to_string(),
+            hint: FORCE_HINT
+        }));
+        graph.push(symlink("/source/dir/a", "/target/dir/a"));
+        graph.push(symlink("/source/b", "/target/b"));
+
+        assert_eq!(graph.nodes()[1].dependencies, vec![0]);
+        assert!(graph.nodes()[2].dependencies.is_empty());
+    }
+
+    #[test]
+    fn test_components() {
+        let mut graph = OperationGraph::new();
+        graph.push(Ok(FSOperation::CreateDir(PathBuf::from("/target/dir"))));
+        graph.push(symlink("/source/b", "/target/b"));
+        graph.push(symlink("/source/dir/a", "/target/dir/a"));
+        graph.push(Ok(FSOperation::DeleteSymlink(PathBuf::from("/target/b"))));
+
+        assert_eq!(graph.components(), vec![vec![0, 2], vec![1, 3]]);
+    }
+
+    #[test]
+    fn test_validate_duplicate_output() {
+        let mut graph = OperationGraph::new();
+        graph.push(symlink("/source/a", "/target/a"));
+        graph.push(symlink("/other/a", "/target/a"));
+
+        assert_eq!(graph.validate().len(), 1);
+    }
+
+    #[test]
+    fn test_validate_parent_created_after() {
+        let mut graph = OperationGraph::new();
+        graph.push(symlink("/source/dir/a", "/target/dir/a"));
+        graph.push(Ok(FSOperation::CreateDir(PathBuf::from("/target/dir"))));
+
+        assert_eq!(graph.validate().len(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+use quicli::prelude::*;
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io::{self, Error};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::result::Result;
+use std::sync::Mutex;
+
+use errors::{AppError, ErrorPath};
+
+/// Runner of the hook commands declared in `.rstow` files.
+/// Use `ShellHookRunner` to run them with `sh` or `MemoryHookRunner` to record them.
+pub trait HookRunner: Debug + Send + Sync {
+    /// Run a `sh` command in `directory` with additional environment variables. A command
+    /// exiting with a non zero status is an error.
+    fn run_command(&self, command: &str, directory: &Path, env: &[(&str, OsString)]) -> io::Result<()>;
+}
+
+/// Run hook commands with `sh -c`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShellHookRunner;
+
+impl HookRunner for ShellHookRunner {
+    fn run_command(&self, command: &str, directory: &Path, env: &[(&str, OsString)]) -> io::Result<()> {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(directory)
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!("`{}` failed with {}", command, status)))
+        }
+    }
+}
+
+/// Hook command recorded by a `MemoryHookRunner`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookCommand {
+    pub command: String,
+    pub directory: PathBuf,
+    pub env: BTreeMap<String, OsString>,
+}
+
+/// Record hook commands without running them, used with a `MemoryFileSystem`
+#[derive(Debug, Default)]
+pub struct MemoryHookRunner {
+    commands: Mutex<Vec<HookCommand>>,
+}
+
+impl MemoryHookRunner {
+    pub fn new() -> MemoryHookRunner {
+        MemoryHookRunner::default()
+    }
+
+    /// Commands given to `run_command` so far
+    pub fn commands(&self) -> Vec<HookCommand> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl HookRunner for MemoryHookRunner {
+    fn run_command(&self, command: &str, directory: &Path, env: &[(&str, OsString)]) -> io::Result<()> {
+        self.commands.lock().unwrap().push(HookCommand {
+            command: command.to_string(),
+            directory: directory.to_path_buf(),
+            env: env.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+        });
+        Ok(())
+    }
+}
+
+/// Environment variables given to a hook command : `RSTOW_HOOK`, `RSTOW_SOURCE`, `RSTOW_TARGET`
+/// and `RSTOW_CHANGED` (changed target paths, one per line)
+pub(crate) fn hook_environment(hook: &str, source: &Path, target: &Path, changed: &[PathBuf]) -> Vec<(&'static str, OsString)> {
+    let mut changed_paths = OsString::new();
+    for (index, path) in changed.iter().enumerate() {
+        if index > 0 {
+            changed_paths.push("\n");
+        }
+        changed_paths.push(path.as_os_str());
+    }
+    vec![
+        ("RSTOW_HOOK", OsString::from(hook)),
+        ("RSTOW_SOURCE", source.as_os_str().to_os_string()),
+        ("RSTOW_TARGET", target.as_os_str().to_os_string()),
+        ("RSTOW_CHANGED", changed_paths),
+    ]
+}
+
+pub(crate) fn run_hook(runner: &dyn HookRunner, hook: &str, command: &str, source: &Path, target: &Path, changed: &[PathBuf]) -> Result<(), AppError> {
+    info!("run {} hook `{}` in {}", hook, command, source.display());
+    runner.run_command(command, source, &hook_environment(hook, source, target, changed)).map_err(|e| AppError::HookError {
+        path: ErrorPath::from(source),
+        hook: hook.to_string(),
+        cause: e.to_string()
+    })
+}
+
+#[cfg(test)]
+mod test_hooks {
+    use super::*;
+    use operations::FSOperation;
+    use stower::Stower;
+    use test_utils::memory_source;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_shell_hook_runner() {
+        let env = hook_environment("post_stow", Path::new("/"), Path::new("/target"), &[PathBuf::from("/target/a"), PathBuf::from("/target/b")]);
+        assert!(ShellHookRunner.run_command("[ \"$RSTOW_CHANGED\" = \"/target/a\n/target/b\" ] && [ \"$RSTOW_HOOK\" = post_stow ] && [ \"$PWD\" = / ]", Path::new("/"), &env).is_ok());
+        assert!(ShellHookRunner.run_command("exit 3", Path::new("/"), &env).is_err());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let fs = memory_source();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\npre_stow = \"echo pre\"\npost_stow = \"fc-cache\"\npost_unstow = \"echo unstowed\"").unwrap();
+        fs.add_file("/source/dir/.rstow", b"symlink_current_dir = true\nignore_files = []\npost_stow = \"echo ignored\"").unwrap();
+        let hooks = Arc::new(MemoryHookRunner::new());
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone()).hooks(hooks.clone());
+        let hook = |hook: &str, command: &str| FSOperation::RunHook {
+            hook: hook.to_string(),
+            command: command.to_string(),
+            source: PathBuf::from("/source"),
+            target: PathBuf::from("/target"),
+            changed: vec![PathBuf::from("/target/dir"), PathBuf::from("/target/file.txt")],
+        };
+
+        // hooks of package directories are ignored
+        let plan = stower.plan().unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![
+            hook("pre_stow", "echo pre"),
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/dir"), target: PathBuf::from("/target/dir") },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/file.txt"), target: PathBuf::from("/target/file.txt") },
+            hook("post_stow", "fc-cache"),
+        ]);
+        // hooks are shown but not run in dry-run
+        assert!(plan.dry_run().is_empty());
+        assert!(hooks.commands().is_empty());
+
+        assert!(plan.apply().is_empty());
+        let commands = hooks.commands();
+        let names: Vec<&str> = commands.iter().map(|c| c.command.as_str()).collect();
+        assert_eq!(names, vec!["echo pre", "fc-cache"]);
+        let post = &commands[1];
+        assert_eq!(post.directory, PathBuf::from("/source"));
+        assert_eq!(post.env["RSTOW_HOOK"], "post_stow");
+        assert_eq!(post.env["RSTOW_SOURCE"], "/source");
+        assert_eq!(post.env["RSTOW_TARGET"], "/target");
+        assert_eq!(post.env["RSTOW_CHANGED"], "/target/dir\n/target/file.txt");
+
+        // nothing changed, no hook
+        assert!(stower.plan().unwrap().operations().all(|op| !matches!(op, Ok(FSOperation::RunHook { .. }))));
+
+        assert!(stower.unstow(true).plan().unwrap().apply().is_empty());
+        let unstowed = hooks.commands().pop().unwrap();
+        assert_eq!(unstowed.command, "echo unstowed");
+        assert_eq!(unstowed.env["RSTOW_HOOK"], "post_unstow");
+    }
+}
@@ -1,66 +1,750 @@
-
 use quicli::prelude::*;
 use im::vector::*;
 
-use std::io;
 use std::path::{Path, PathBuf};
-use std::collections::LinkedList;
 use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::SystemTime;
+use std::os::unix::ffi::OsStrExt;
 
 use fileutils::*;
-use operations::FSOperation;
-use errors::AppError;
-use std::fs::create_dir_all;
+use operations::{owner_spec, FSOperation};
+use errors::{io_error, AppError, ErrorPath};
+use graph::OperationGraph;
+use filesystem::{FileSystem, FileType};
+use hooks::{hook_environment, run_hook, HookRunner, ShellHookRunner};
+use verify::unified_diff;
+
+/// Executor of planned operations. Operations may be interpreted from several threads
+/// (see `apply_graph`), implementations needing a state must synchronize it.
+pub trait Interpreter: Sync {
+    /// Execute a single operation
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError>;
+
+    /// Called for each planning error met while previewing a plan
+    fn error(&self, _err: &AppError) {}
+
+    /// An interpreter only describing operations (without affecting files) is given every
+    /// operation and planning error, even from an invalid plan
+    fn is_preview(&self) -> bool {
+        false
+    }
+}
+
+/// Print operations that should be done (and planning errors) without affecting files.
+/// A file deleted to be replaced is previewed with a diff and a size and modification time summary.
+#[derive(Debug)]
+pub struct DryRunInterpreter {
+    fs: Arc<dyn FileSystem>,
+    // files deleted so far, not replaced yet
+    deleted: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl DryRunInterpreter {
+    pub fn new(fs: Arc<dyn FileSystem>) -> DryRunInterpreter {
+        DryRunInterpreter { fs, deleted: Mutex::new(BTreeSet::new()) }
+    }
+
+    fn print_overwrite(&self, source: &Path, target: &Path, diff: bool) -> Result<(), AppError> {
+        if self.deleted.lock().unwrap().remove(target) {
+            print!("{}", overwrite_preview(self.fs.as_ref(), source, target, diff, SystemTime::now())?);
+        }
+        Ok(())
+    }
+}
+
+impl Interpreter for DryRunInterpreter {
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError> {
+        match op {
+            FSOperation::Nothing{path, cause} => println!("DRY-RUN : nothing to do on {} ({})", path.display(), cause),
+            FSOperation::Backup(p) => println!("DRY-RUN : backup {}", p.display()),
+            FSOperation::CreateDir(p) => println!("DRY-RUN : create directory {}", p.display()),
+            FSOperation::Restore {backup, target} => println!("DRY-RUN : restore {} -> {}", backup.display(), target.display()),
+            FSOperation::BreakDirectoryLink(p) => println!("DRY-RUN : Break directory link {} and rebuild children links", p.display()),
+            FSOperation::DeleteSymlink(p) => println!("DRY-RUN : delete symbolic link {}", p.display()),
+            FSOperation::DeleteFile(p) => {
+                println!("DRY-RUN : delete file {}", p.display());
+                self.deleted.lock().unwrap().insert(p.to_path_buf());
+            },
+            FSOperation::DeleteEmptyDir(p) => println!("DRY-RUN : delete empty directory {}", p.display()),
+            FSOperation::DeleteDirRecursive(p) => println!("DRY-RUN : delete directory recursively {}", p.display()),
+            FSOperation::CreateSymlink{source, target} => {
+                println!("DRY-RUN : create symbolic link {} -> {}", source.display(), target.display());
+                self.print_overwrite(source, target, true)?;
+            },
+            FSOperation::ReplaceSymlink{source, target} => println!("DRY-RUN : replace symbolic link {} -> {}", source.display(), target.display()),
+            FSOperation::RunHook{hook, command, source, ..} => println!("DRY-RUN : run {} hook `{}` in {}", hook, command, source.display()),
+            FSOperation::SetMode{path, mode} => println!("DRY-RUN : set mode {:04o} on {}", mode, path.display()),
+            FSOperation::SetOwner{path, uid, gid} => println!("DRY-RUN : set owner {} on {}", owner_spec(*uid, *gid), path.display()),
+            FSOperation::Decrypt{source, target, ..} => {
+                println!("DRY-RUN : decrypt {} -> {}", source.display(), target.display());
+                // decrypted content is not shown
+                self.print_overwrite(source, target, false)?;
+            },
+            FSOperation::SecureDelete(p) => println!("DRY-RUN : securely delete {}", p.display()),
+        };
+        Ok(())
+    }
+
+    fn error(&self, err: &AppError) {
+        eprintln!("DRY-RUN : Error {}", err)
+    }
+
+    fn is_preview(&self) -> bool {
+        true
+    }
+}
+
+/// Apply operations on a `FileSystem`
+#[derive(Debug)]
+pub struct FileSystemInterpreter {
+    fs: Arc<dyn FileSystem>,
+    hooks: Arc<dyn HookRunner>,
+}
+
+impl FileSystemInterpreter {
+    /// Apply operations on `fs`, running hooks with a `ShellHookRunner`
+    pub fn new(fs: Arc<dyn FileSystem>) -> FileSystemInterpreter {
+        FileSystemInterpreter { fs, hooks: Arc::new(ShellHookRunner) }
+    }
+
+    /// Runner of hook commands
+    pub fn hooks(mut self, hooks: Arc<dyn HookRunner>) -> FileSystemInterpreter {
+        self.hooks = hooks;
+        self
+    }
+}
 
+impl Interpreter for FileSystemInterpreter {
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError> {
+        let fs = self.fs.as_ref();
+        match op {
+            FSOperation::Nothing {path, cause} => info!("Nothing to do on {} ({})", path.display(), cause),
+            FSOperation::Backup(p) => backup_path(fs, p.as_path())?,
+            FSOperation::CreateDir(p) => fs.create_dir_all(p.as_path()).map_err(io_error(p))?,
+            FSOperation::DeleteSymlink(p) => delete_path(fs, p.as_path(), FileType::Symlink, false)?,
+            FSOperation::DeleteFile(p) => delete_path(fs, p.as_path(), FileType::File, false)?,
+            FSOperation::DeleteEmptyDir(p) => delete_path(fs, p.as_path(), FileType::Dir, false)?,
+            FSOperation::DeleteDirRecursive(p) => delete_path(fs, p.as_path(), FileType::Dir, true)?,
+            FSOperation::Restore {backup, target} => restore_path(fs, backup.as_path(), target.as_path()).map_err(io_error(target))?,
+            FSOperation::CreateSymlink{source, target} => create_symlink(fs, source.as_path(), target.as_path()).map_err(io_error(target))?,
+            FSOperation::ReplaceSymlink{source, target} => replace_symlink(fs, source.as_path(), target.as_path())?,
+            FSOperation::BreakDirectoryLink(p) => break_directory_link(fs, p.as_path())?,
+            FSOperation::RunHook{hook, command, source, target, changed} => run_hook(self.hooks.as_ref(), hook, command, source, target, changed)?,
+            FSOperation::SetMode{path, mode} => {
+                info!("set mode {:04o} on {}", mode, path.display());
+                fs.set_mode(path, *mode).map_err(io_error(path))?
+            },
+            FSOperation::SetOwner{path, uid, gid} => {
+                info!("set owner {} on {}", owner_spec(*uid, *gid), path.display());
+                fs.set_owner(path, *uid, *gid).map_err(io_error(path))?
+            },
+            FSOperation::Decrypt{source, target, identity} => decrypt_file(fs, source, target, identity)?,
+            FSOperation::SecureDelete(p) => secure_delete(fs, p).map_err(io_error(p))?,
+        };
+        Ok(())
+    }
+}
+
+/// Log operations (and planning errors) without affecting files
+#[derive(Debug, Default)]
+pub struct LoggingInterpreter;
+
+impl Interpreter for LoggingInterpreter {
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError> {
+        info!("{}", op);
+        Ok(())
+    }
+
+    fn error(&self, err: &AppError) {
+        error!("{}", err)
+    }
 
-pub(crate) fn dryrun_interpreter(operations: &Vector<Result<FSOperation, AppError>>) -> Result<(), AppError> {
-    let mut has_error = false;
-    for result in operations.iter() {
+    fn is_preview(&self) -> bool {
+        true
+    }
+}
+
+/// Record interpreted operations without affecting files, mostly useful in tests
+#[derive(Debug, Default)]
+pub struct RecordingInterpreter {
+    operations: Mutex<Vec<FSOperation>>,
+}
+
+impl RecordingInterpreter {
+    pub fn new() -> RecordingInterpreter {
+        RecordingInterpreter::default()
+    }
+
+    /// Operations interpreted so far, in interpretation order
+    pub fn operations(&self) -> Vec<FSOperation> {
+        self.operations.lock().unwrap().clone()
+    }
+}
+
+impl Interpreter for RecordingInterpreter {
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError> {
+        self.operations.lock().unwrap().push(op.clone());
+        Ok(())
+    }
+}
+
+/// Write operations as a POSIX `sh` script to review and run later. Each command is guarded
+/// by a re-check of its preconditions, the script stops at the first failure (`set -e`).
+/// Planning errors are written as comments.
+#[derive(Debug)]
+pub struct ShellScriptInterpreter {
+    fs: Arc<dyn FileSystem>,
+    lines: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ShellScriptInterpreter {
+    pub fn new(fs: Arc<dyn FileSystem>) -> ShellScriptInterpreter {
+        ShellScriptInterpreter { fs, lines: Mutex::new(Vec::new()) }
+    }
+
+    /// Full script content with operations interpreted so far
+    pub fn script(&self) -> Vec<u8> {
+        let mut script: Vec<u8> = b"#!/bin/sh\n# generated by rstow\nset -e\n\nfail() {\n    printf 'rstow: %s\\n' \"$1\" >&2\n    exit 1\n}\n\n".to_vec();
+        for line in self.lines.lock().unwrap().iter() {
+            script.extend_from_slice(line);
+            script.push(b'\n');
+        }
+        script
+    }
+
+    fn push(&self, parts: &[&[u8]]) {
+        self.lines.lock().unwrap().push(parts.concat());
+    }
+
+    // `[ ... ] || fail '<message> <path>'`
+    fn guard(&self, condition: &[u8], message: &str, path: &Path) {
+        let message = shell_quote(format!("{} ", message).as_bytes());
+        self.push(&[condition, b" || fail ", &message, &shell_quote(path.as_os_str().as_bytes())]);
+    }
+}
+
+impl Interpreter for ShellScriptInterpreter {
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError> {
+        self.push(&[b"# ", op.to_string().replace('\n', " ").as_bytes()]);
+        match op {
+            FSOperation::Nothing{..} => (),
+            FSOperation::Backup(p) => {
+                let backup = build_backup_path(p.as_path())?;
+                self.guard(&exists(p), "missing path to backup", p);
+                self.guard(&absent(backup.as_path()), "backup already exists", backup.as_path());
+                self.push(&[b"mv -- ", &quote_path(p), b" ", &quote_path(backup.as_path())]);
+            },
+            FSOperation::CreateDir(p) => {
+                let quoted = quote_path(p);
+                self.guard(&[&absent(p)[..], b" || [ -d ", &quoted, b" ]"].concat(), "not a directory", p);
+                self.push(&[b"mkdir -p -- ", &quoted]);
+            },
+            FSOperation::DeleteSymlink(p) => {
+                self.guard(&[&b"[ -L "[..], &quote_path(p), b" ]"].concat(), "not a symbolic link", p);
+                self.push(&[b"rm -f -- ", &quote_path(p)]);
+            },
+            FSOperation::DeleteFile(p) => {
+                let quoted = quote_path(p);
+                self.guard(&[b"[ -f ", &quoted[..], b" ] && [ ! -L ", &quoted, b" ]"].concat(), "not a regular file", p);
+                self.push(&[b"rm -f -- ", &quoted]);
+            },
+            FSOperation::DeleteEmptyDir(p) => {
+                let quoted = quote_path(p);
+                self.guard(&real_dir(p), "not a directory", p);
+                self.push(&[b"rmdir -- ", &quoted]);
+            },
+            FSOperation::DeleteDirRecursive(p) => {
+                self.guard(&real_dir(p), "not a directory", p);
+                self.push(&[b"rm -rf -- ", &quote_path(p)]);
+            },
+            FSOperation::Restore {backup, target} => {
+                self.guard(&exists(backup), "missing backup", backup);
+                self.guard(&absent(target), "target already exists", target);
+                self.push(&[b"mv -- ", &quote_path(backup), b" ", &quote_path(target)]);
+            },
+            FSOperation::CreateSymlink{source, target} => {
+                self.guard(&exists(source), "missing source", source);
+                self.guard(&absent(target), "target already exists", target);
+                self.push(&[b"ln -s -- ", &quote_path(source), b" ", &quote_path(target)]);
+            },
+            FSOperation::ReplaceSymlink{source, target} => {
+                let temporary = quote_path(build_temporary_path(target)?.as_path());
+                self.guard(&exists(source), "missing source", source);
+                self.guard(&[&b"[ -L "[..], &quote_path(target), b" ]"].concat(), "not a symbolic link", target);
+                // POSIX `mv` moves into a linked directory instead of replacing the link
+                self.guard(&[&b"[ ! -d "[..], &quote_path(target), b" ]"].concat(), "symbolic link to a directory", target);
+                self.push(&[b"rm -f -- ", &temporary]);
+                self.push(&[b"ln -s -- ", &quote_path(source), b" ", &temporary]);
+                self.push(&[b"mv -f -- ", &temporary, b" ", &quote_path(target)]);
+            },
+            FSOperation::BreakDirectoryLink(p) => {
+                let link_target = get_symlink_target(self.fs.as_ref(), p).ok_or_else(|| AppError::MissingTarget { path: ErrorPath::from(p.as_path()) })?;
+                let quoted = quote_path(p);
+                self.guard(&[b"[ -L ", &quoted[..], b" ] && [ -d ", &quoted, b" ]"].concat(), "not a directory link", p);
+                self.push(&[b"rm -f -- ", &quoted]);
+                self.push(&[b"mkdir -- ", &quoted]);
+                for child in self.fs.read_dir(link_target.as_path()).map_err(io_error(link_target.as_path()))? {
+                    let target_child = p.join(file_name(child.as_path())?);
+                    self.push(&[b"ln -s -- ", &quote_path(child.as_path()), b" ", &quote_path(target_child.as_path())]);
+                }
+            },
+            FSOperation::SetMode{path, mode} => {
+                self.guard(&exists(path), "missing path", path);
+                self.push(&[format!("chmod {:04o} -- ", mode).as_bytes(), &quote_path(path)]);
+            },
+            FSOperation::SetOwner{path, uid, gid} => {
+                self.guard(&exists(path), "missing path", path);
+                self.push(&[format!("chown -h {} -- ", owner_spec(*uid, *gid)).as_bytes(), &quote_path(path)]);
+            },
+            FSOperation::Decrypt{source, target, identity} => {
+                self.guard(&exists(source), "missing source", source);
+                self.guard(&absent(target), "target already exists", target);
+                self.guard(b"command -v age >/dev/null", "age command required to decrypt", source);
+                self.push(&[b"(umask 077 && age -d -i ", &quote_path(identity), b" -o ", &quote_path(target), b" -- ", &quote_path(source), b")"]);
+            },
+            FSOperation::SecureDelete(p) => {
+                let quoted = quote_path(p);
+                self.guard(&[b"[ -f ", &quoted[..], b" ] && [ ! -L ", &quoted, b" ]"].concat(), "not a regular file", p);
+                // `shred` is not POSIX, overwrite the file in place with `dd` when missing
+                self.push(&[b"if command -v shred >/dev/null; then shred -u -- ", &quoted,
+                    b"; else dd if=/dev/zero of=", &quoted, b" bs=1 count=$(($(wc -c < ", &quoted, b"))) conv=notrunc 2>/dev/null && sync && rm -f -- ", &quoted, b"; fi"]);
+            },
+            FSOperation::RunHook{hook, command, source, target, changed} => {
+                let mut line: Vec<u8> = [&b"(cd -- "[..], &quote_path(source), b" &&"].concat();
+                for (name, value) in hook_environment(hook, source, target, changed) {
+                    line.extend_from_slice(format!(" {}=", name).as_bytes());
+                    line.extend(shell_quote(value.as_bytes()));
+                }
+                self.push(&[&line, b" sh -c ", &shell_quote(command.as_bytes()), b")"]);
+            },
+        };
+        Ok(())
+    }
+
+    fn error(&self, err: &AppError) {
+        self.push(&[b"# error : ", err.to_string().replace('\n', " ").as_bytes()]);
+    }
+
+    fn is_preview(&self) -> bool {
+        true
+    }
+}
+
+/// Size and modification time of an overwritten `target` file and of the `source` file replacing
+/// it, followed by a unified diff from target to source if `diff` is set
+fn overwrite_preview(fs: &dyn FileSystem, source: &Path, target: &Path, diff: bool, now: SystemTime) -> Result<String, AppError> {
+    let target_content = fs.read(target).map_err(io_error(target))?;
+    let source_content = fs.read(source).map_err(io_error(source))?;
+    let target_modified = fs.modified(target).map_err(io_error(target))?;
+    let source_modified = fs.modified(source).map_err(io_error(source))?;
+
+    let mut preview = format!("  overwritten {} : {} bytes, modified {}{}\n",
+        target.display(), target_content.len(), elapsed(target_modified, now),
+        if target_modified > source_modified { " (newer than source)" } else { "" });
+    preview.push_str(&format!("  by {} : {} bytes, modified {}\n",
+        source.display(), source_content.len(), elapsed(source_modified, now)));
+    if diff {
+        match unified_diff(target, source, &target_content, &source_content) {
+            Some(diff) => preview.push_str(&diff),
+            None => preview.push_str("  binary files differ\n"),
+        }
+    }
+    Ok(preview)
+}
+
+/// Elapsed time like `3 days ago`
+fn elapsed(time: SystemTime, now: SystemTime) -> String {
+    let seconds = match now.duration_since(time) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => return "in the future".to_string(),
+    };
+    let (value, unit) = match seconds {
+        s if s < 60 => (s, "second"),
+        s if s < 3600 => (s / 60, "minute"),
+        s if s < 86400 => (s / 3600, "hour"),
+        s => (s / 86400, "day"),
+    };
+    format!("{} {}{} ago", value, unit, if value == 1 { "" } else { "s" })
+}
+
+/// Quote arbitrary bytes as a single `sh` word
+fn shell_quote(value: &[u8]) -> Vec<u8> {
+    let mut quoted: Vec<u8> = vec![b'\''];
+    for byte in value {
+        if *byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(*byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+fn quote_path(path: &Path) -> Vec<u8> {
+    shell_quote(path.as_os_str().as_bytes())
+}
+
+fn exists(path: &Path) -> Vec<u8> {
+    let quoted = quote_path(path);
+    [&b"{ [ -e "[..], &quoted, b" ] || [ -L ", &quoted, b" ]; }"].concat()
+}
+
+// a directory, not a link to a directory
+fn real_dir(path: &Path) -> Vec<u8> {
+    let quoted = quote_path(path);
+    [&b"[ -d "[..], &quoted, b" ] && [ ! -L ", &quoted, b" ]"].concat()
+}
+
+fn absent(path: &Path) -> Vec<u8> {
+    let quoted = quote_path(path);
+    [&b"{ [ ! -e "[..], &quoted, b" ] && [ ! -L ", &quoted, b" ]; }"].concat()
+}
+
+/// Give every operation and planning error of the graph to a (preview) interpreter,
+/// returning errors raised by the interpreter
+pub fn preview_graph(interpreter: &dyn Interpreter, operations: &OperationGraph) -> Vec<AppError> {
+    let mut errors: Vec<AppError> = Vec::new();
+    for result in operations.operations() {
         match result {
             Ok(op) => {
-                match op {
-                    FSOperation::Nothing{path, cause} => println!("DRY-RUN : nothing to do on {} ({})", path.display(), cause),
-                    FSOperation::Backup(p) => println!("DRY-RUN : backup {}", p.display()),
-                    FSOperation::CreateDir(p) => println!("DRY-RUN : create directory {}", p.display()),
-                    FSOperation::Restore {backup, target} => println!("DRY-RUN : restore {} -> {}", backup.display(), target.display()),
-                    FSOperation::BreakDirectoryLink(p) => println!("DRY-RUN : Break directory link {} and rebuild children links", p.display()),
-                    FSOperation::Delete(p) => {
-                        if p.is_dir() {
-                            println!("DRY-RUN : delete directory recursively {}", p.display());
-                        } else {
-                            println!("DRY-RUN : delete file {}", p.display());
-                        }
-                    }
-                    FSOperation::CreateSymlink{source, target} => println!("DRY-RUN : create symbolic link {} -> {}", source.display(), target.display()),
-                };
+                if let Err(e) = interpreter.interpret(op) {
+                    errors.push(e);
+                }
             },
-            Err(err) => {
-                has_error = true;
-                eprintln!("DRY-RUN : Error {}", err)
-            }
+            Err(err) => interpreter.error(err),
         }
     };
+    errors
+}
 
-    if has_error {
-        error!("{}", AppError::ApplyError)
+/// Apply operations in order, stopping at the first error. Operations applied before the
+/// error are then rolled back (see `rollback`).
+pub fn apply_operations(interpreter: &dyn Interpreter, operations: &Vector<&FSOperation>) -> GraphApplyResult {
+    let mut result = GraphApplyResult::default();
+    for (index, op) in operations.iter().enumerate() {
+        if let Err(err) = interpreter.interpret(op) {
+            result.errors.push(err);
+            let applied: Vec<&FSOperation> = operations.iter().take(index).cloned().collect();
+            let (rolled_back, errors) = rollback(interpreter, &applied);
+            result.rolled_back = rolled_back;
+            result.errors.extend(errors);
+            break;
+        }
+    };
+    result
+}
+
+/// Revert `applied` operations from the last one, returning the reverted operations and
+/// the errors raised by their undo operations.
+///
+/// Rollback stops at the first operation that can't be undone (see `FSOperation::undo`) or
+/// whose undo fails : previous operations are kept, the state they produced is still needed.
+/// Mode and owner changes on a path created by a previous operation are not undone, the path
+/// being removed anyway.
+pub fn rollback(interpreter: &dyn Interpreter, applied: &[&FSOperation]) -> (Vec<FSOperation>, Vec<AppError>) {
+    let mut rolled_back: Vec<FSOperation> = Vec::new();
+    for (index, op) in applied.iter().enumerate().rev() {
+        let undo = match op {
+            FSOperation::SetMode { path, .. } | FSOperation::SetOwner { path, .. }
+                if applied[..index].iter().any(|previous| previous.produces().contains(path)) => Some(Vec::new()),
+            _ => op.undo(),
+        };
+        let undo = match undo {
+            Some(undo) => undo,
+            None => {
+                warn!("Rollback stopped, unable to undo : {}", op);
+                break;
+            }
+        };
+        if let Some(err) = undo.iter().filter_map(|u| interpreter.interpret(u).err()).next() {
+            return (rolled_back, vec![err]);
+        }
+        rolled_back.push((*op).clone());
     }
-    Ok(())
+    (rolled_back, Vec::new())
 }
 
-pub(crate) fn filesystem_interpreter(operations: &Vector<&FSOperation>) -> Result<(), AppError> {
-    for op in operations.iter() {
-        match op {
-            FSOperation::Nothing {path, cause} => {
-                info!("Nothing to do on {} ({})", path.display(), cause);
-                Ok(())
-            },
-            FSOperation::Backup(p) => backup_path(p.as_path()),
-            FSOperation::CreateDir(p) => create_dir_all(p.as_path()),
-            FSOperation::Delete(p) => delete_path(p.as_path()),
-            FSOperation::Restore {backup, target} => restore_path(backup.as_path(), target.as_path()),
-            FSOperation::CreateSymlink{source, target} => create_symlink(source.as_path(), target.as_path()),
-            FSOperation::BreakDirectoryLink(p) => break_directory_link(p.as_path()),
+/// Errors raised when applying an operation graph (in plan order, planning errors excluded), operations skipped
+/// because they depend on a failed one and operations rolled back after a failure
+#[derive(Debug, Default)]
+pub struct GraphApplyResult {
+    pub errors: Vec<AppError>,
+    pub skipped: Vec<FSOperation>,
+    pub rolled_back: Vec<FSOperation>,
+}
+
+/// Apply operations using `jobs` threads. Independent groups of operations (see `OperationGraph::components`)
+/// are applied concurrently while operations of a same group are applied in plan order.
+/// Errors are reported in plan order whatever the thread that raised them. Planning errors are
+/// not reported again, they only fail the operations depending on them.
+///
+/// Without `keep_going`, a group stops at its first error and its applied operations are
+/// rolled back. With `keep_going`, only operations depending on a failed one (or on a planning
+/// error) are skipped and nothing is rolled back.
+pub fn apply_graph(interpreter: &dyn Interpreter, operations: &OperationGraph, jobs: usize, keep_going: bool) -> GraphApplyResult {
+    let components = operations.components();
+    let next_component = AtomicUsize::new(0);
+    let errors: Mutex<Vec<(usize, AppError)>> = Mutex::new(Vec::new());
+    let skipped: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    let rolled_back: Mutex<Vec<(usize, FSOperation)>> = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| {
+                while let Some(component) = components.get(next_component.fetch_add(1, Ordering::SeqCst)) {
+                    let mut failed: BTreeSet<usize> = BTreeSet::new();
+                    let mut applied: Vec<&FSOperation> = Vec::new();
+                    for index in component.iter() {
+                        let node = &operations.nodes()[*index];
+                        if node.dependencies.iter().any(|d| failed.contains(d)) {
+                            debug!("Skip operation {} depending on a failed one", index);
+                            failed.insert(*index);
+                            skipped.lock().unwrap().push(*index);
+                            continue;
+                        }
+
+                        // a planning error is already reported with the plan
+                        if let Ok(op) = &node.operation {
+                            match interpreter.interpret(op) {
+                                Ok(()) => {
+                                    applied.push(op);
+                                    continue;
+                                },
+                                Err(err) => errors.lock().unwrap().push((*index, err)),
+                            }
+                        }
+                        if !keep_going {
+                            // stop this group, following operations may depend on the failed one
+                            let (undone, undo_errors) = rollback(interpreter, &applied);
+                            rolled_back.lock().unwrap().extend(undone.into_iter().map(|op| (*index, op)));
+                            errors.lock().unwrap().extend(undo_errors.into_iter().map(|e| (*index, e)));
+                            break;
+                        }
+                        failed.insert(*index);
+                    }
+                }
+            });
+        }
+    });
+
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by_key(|(index, _)| *index);
+    let mut skipped = skipped.into_inner().unwrap();
+    skipped.sort();
+    let mut rolled_back = rolled_back.into_inner().unwrap();
+    // stable sort : operations of a group stay in rollback order
+    rolled_back.sort_by_key(|(index, _)| *index);
+
+    GraphApplyResult {
+        errors: errors.into_iter().map(|(_, err)| err).collect(),
+        skipped: skipped.into_iter()
+            .filter_map(|index| operations.nodes()[index].operation.as_ref().ok().cloned())
+            .collect(),
+        rolled_back: rolled_back.into_iter().map(|(_, op)| op).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test_interpreters {
+    use super::*;
+    use errors::FORCE_HINT;
+    use filesystem::MemoryFileSystem;
+    use hooks::MemoryHookRunner;
+    use std::ffi::OsStr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(b"/target/file"), b"'/target/file'".to_vec());
+        assert_eq!(shell_quote(b"it's $HOME"), b"'it'\\''s $HOME'".to_vec());
+        assert_eq!(shell_quote(b"\xff\n"), b"'\xff\n'".to_vec());
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let hooks = Arc::new(MemoryHookRunner::new());
+        let interpreter = FileSystemInterpreter::new(Arc::new(MemoryFileSystem::new())).hooks(hooks.clone());
+        let hook = FSOperation::RunHook {
+            hook: "post_stow".to_string(),
+            command: "true".to_string(),
+            source: PathBuf::from("/"),
+            target: PathBuf::from("/target"),
+            changed: vec![PathBuf::from("/target/a"), PathBuf::from("/target/b")],
         };
-    };
-    Ok(())
+
+        assert!(interpreter.interpret(&hook).is_ok());
+        assert_eq!(hooks.commands()[0].env["RSTOW_CHANGED"], "/target/a\n/target/b");
+    }
+
+    #[test]
+    fn test_apply_graph_errors() {
+        let fs = Arc::new(MemoryFileSystem::new());
+        fs.add_file("/source/a/b", b"").unwrap();
+        fs.add_file("/source/c", b"").unwrap();
+        fs.add_file("/target/a", b"").unwrap();
+        let interpreter = FileSystemInterpreter::new(fs.clone());
+        let link = |name: &str| FSOperation::CreateSymlink { source: PathBuf::from("/source").join(name), target: PathBuf::from("/target").join(name) };
+
+        let mut graph = OperationGraph::new();
+        graph.push(Err(AppError::StowPathError {
+            source: ErrorPath::from(Path::new("/source/a")),
+            target: ErrorPath::from(Path::new("/target/a")),
+            cause: "Target is a file".to_string(),
+            hint: FORCE_HINT
+        }));
+        graph.push(Ok(link("a/b")));
+        graph.push(Ok(link("c")));
+        graph.push(Ok(FSOperation::DeleteFile(PathBuf::from("/target/missing"))));
+        graph.push(Ok(FSOperation::SetMode { path: PathBuf::from("/target/missing"), mode: 0o600 }));
+
+        // only the apply failure is reported, dependents of both errors are skipped
+        let result = apply_graph(&interpreter, &graph, 2, true);
+        assert_eq!(result.errors.len(), 1);
+        assert!(matches!(&result.errors[0], AppError::PathChanged { path, .. } if path.as_path() == Path::new("/target/missing")));
+        assert_eq!(result.skipped, vec![link("a/b"), FSOperation::SetMode { path: PathBuf::from("/target/missing"), mode: 0o600 }]);
+        assert!(fs.is_symlink(Path::new("/target/c")));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let fs = Arc::new(MemoryFileSystem::new());
+        fs.add_file("/source/a", b"").unwrap();
+        fs.add_file("/target/file", b"").unwrap();
+        fs.add_file("/target/old", b"").unwrap();
+        let interpreter = FileSystemInterpreter::new(fs.clone());
+        let link = FSOperation::CreateSymlink { source: PathBuf::from("/source/a"), target: PathBuf::from("/target/new/a") };
+        let operations = [
+            FSOperation::CreateDir(PathBuf::from("/target/new")),
+            FSOperation::SetMode { path: PathBuf::from("/target/new"), mode: 0o700 },
+            link.clone(),
+            FSOperation::Backup(PathBuf::from("/target/file")),
+            FSOperation::DeleteFile(PathBuf::from("/target/new/missing")),
+        ];
+
+        // only the group of the failed operation is rolled back
+        let mut graph = OperationGraph::new();
+        for op in operations.iter() {
+            graph.push(Ok(op.clone()));
+        }
+        let result = apply_graph(&interpreter, &graph, 2, false);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.rolled_back, vec![link.clone(), operations[1].clone(), operations[0].clone()]);
+        assert!(!fs.exists(Path::new("/target/new")));
+        assert!(fs.exists(Path::new("/target/file.backup")));
+
+        // rollback stops at an operation that can't be undone
+        let delete = FSOperation::DeleteFile(PathBuf::from("/target/old"));
+        let operations: Vector<&FSOperation> = vec![
+            &operations[0],
+            &delete,
+            &link,
+            &operations[4],
+        ].into_iter().collect();
+        let result = apply_operations(&interpreter, &operations);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.rolled_back, vec![link.clone()]);
+        assert!(!fs.exists(Path::new("/target/new/a")));
+        assert!(fs.is_dir(Path::new("/target/new")));
+    }
+
+    #[test]
+    fn test_delete_checks() {
+        let fs = Arc::new(MemoryFileSystem::new());
+        fs.add_file("/source/dir/a", b"").unwrap();
+        fs.add_file("/target/dir/a", b"").unwrap();
+        fs.add_dir("/target/empty").unwrap();
+        fs.symlink(Path::new("/source/dir"), Path::new("/target/link")).unwrap();
+        let interpreter = FileSystemInterpreter::new(fs.clone());
+        let changed = |res: Result<(), AppError>| matches!(res, Err(AppError::PathChanged { .. }));
+
+        // the planned type is checked again before deleting
+        assert!(changed(interpreter.interpret(&FSOperation::DeleteSymlink(PathBuf::from("/target/dir")))));
+        assert!(changed(interpreter.interpret(&FSOperation::DeleteFile(PathBuf::from("/target/link")))));
+        assert!(changed(interpreter.interpret(&FSOperation::DeleteEmptyDir(PathBuf::from("/target/dir")))));
+        assert!(changed(interpreter.interpret(&FSOperation::DeleteDirRecursive(PathBuf::from("/target/link")))));
+        assert!(fs.exists(Path::new("/target/dir/a")));
+        assert!(fs.exists(Path::new("/source/dir/a")));
+
+        interpreter.interpret(&FSOperation::DeleteEmptyDir(PathBuf::from("/target/empty"))).unwrap();
+        interpreter.interpret(&FSOperation::DeleteDirRecursive(PathBuf::from("/target/dir"))).unwrap();
+        assert!(!fs.exists(Path::new("/target/empty")));
+        assert!(!fs.exists(Path::new("/target/dir")));
+
+        interpreter.interpret(&FSOperation::BreakDirectoryLink(PathBuf::from("/target/link"))).unwrap();
+        assert!(!fs.is_symlink(Path::new("/target/link")));
+        assert_eq!(fs.read_link(Path::new("/target/link/a")).unwrap(), PathBuf::from("/source/dir/a"));
+        assert!(fs.exists(Path::new("/source/dir/a")));
+    }
+
+    #[test]
+    fn test_overwrite_preview() {
+        let fs = MemoryFileSystem::new();
+        let now = SystemTime::now();
+        fs.add_file("/source/file", b"one\ntwo\n").unwrap();
+        fs.add_file("/target/file", b"one\nlocal\n").unwrap();
+        fs.set_modified("/source/file", now - Duration::from_secs(3 * 86400)).unwrap();
+        fs.set_modified("/target/file", now - Duration::from_secs(7200)).unwrap();
+
+        let preview = overwrite_preview(&fs, Path::new("/source/file"), Path::new("/target/file"), true, now).unwrap();
+        assert_eq!(preview, concat!(
+            "  overwritten /target/file : 10 bytes, modified 2 hours ago (newer than source)\n",
+            "  by /source/file : 8 bytes, modified 3 days ago\n",
+            "--- /target/file\n+++ /source/file\n@@ -1,2 +1,2 @@\n one\n-local\n+two\n"));
+
+        let preview = overwrite_preview(&fs, Path::new("/source/file"), Path::new("/target/file"), false, now).unwrap();
+        assert!(!preview.contains("local"));
+        assert_eq!(elapsed(now, now - Duration::from_secs(1)), "in the future");
+        assert_eq!(elapsed(now - Duration::from_secs(60), now), "1 minute ago");
+    }
+
+    #[test]
+    fn test_replace_symlink() {
+        let fs = Arc::new(MemoryFileSystem::new());
+        fs.add_file("/source/file", b"").unwrap();
+        fs.add_file("/other/file", b"").unwrap();
+        fs.add_dir("/target").unwrap();
+        fs.symlink(Path::new("/other/file"), Path::new("/target/file")).unwrap();
+        // left by an interrupted run
+        fs.symlink(Path::new("/other/file"), Path::new("/target/.file.rstow-tmp")).unwrap();
+
+        let interpreter = FileSystemInterpreter::new(fs.clone());
+        interpreter.interpret(&FSOperation::ReplaceSymlink { source: PathBuf::from("/source/file"), target: PathBuf::from("/target/file") }).unwrap();
+        assert_eq!(fs.read_link(Path::new("/target/file")).unwrap(), PathBuf::from("/source/file"));
+        assert_eq!(fs.read_dir(Path::new("/target")).unwrap(), vec![PathBuf::from("/target/file")]);
+
+        let interpreter = ShellScriptInterpreter::new(fs);
+        interpreter.interpret(&FSOperation::ReplaceSymlink { source: PathBuf::from("/source/file"), target: PathBuf::from("/target/file") }).unwrap();
+        let script = String::from_utf8(interpreter.script()).unwrap();
+        assert!(script.contains("[ ! -d '/target/file' ] || fail 'symbolic link to a directory ''/target/file'\n"));
+        assert!(script.contains("ln -s -- '/source/file' '/target/.file.rstow-tmp'\nmv -f -- '/target/.file.rstow-tmp' '/target/file'\n"));
+    }
+
+    #[test]
+    fn test_shell_script() {
+        let interpreter = ShellScriptInterpreter::new(Arc::new(MemoryFileSystem::new()));
+        let target = PathBuf::from(OsStr::from_bytes(b"/target/a b"));
+        interpreter.interpret(&FSOperation::Backup(target.clone())).unwrap();
+        interpreter.interpret(&FSOperation::CreateSymlink { source: PathBuf::from("/source/a b"), target }).unwrap();
+
+        let script = String::from_utf8(interpreter.script()).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("set -e\n"));
+        assert!(script.contains("mv -- '/target/a b' '/target/a b.backup'\n"));
+        assert!(script.contains("{ [ ! -e '/target/a b' ] && [ ! -L '/target/a b' ]; } || fail 'target already exists ''/target/a b'\n"));
+        assert!(script.contains("ln -s -- '/source/a b' '/target/a b'\n"));
+
+        // commands missing from POSIX systems are checked or replaced
+        let interpreter = ShellScriptInterpreter::new(Arc::new(MemoryFileSystem::new()));
+        let target = PathBuf::from("/target/.netrc");
+        interpreter.interpret(&FSOperation::Decrypt { source: PathBuf::from("/source/dot-netrc.age"), target: target.clone(), identity: PathBuf::from("/keys/identity.txt") }).unwrap();
+        interpreter.interpret(&FSOperation::SecureDelete(target)).unwrap();
+        let script = String::from_utf8(interpreter.script()).unwrap();
+        assert!(script.contains("command -v age >/dev/null || fail 'age command required to decrypt ''/source/dot-netrc.age'\n"));
+        assert!(script.contains("else dd if=/dev/zero of='/target/.netrc' bs=1 count=$(($(wc -c < '/target/.netrc'))) conv=notrunc 2>/dev/null && sync && rm -f -- '/target/.netrc'; fi\n"));
+    }
 }
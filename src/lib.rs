@@ -0,0 +1,68 @@
+//! Like stow but simpler and with more crabs.
+//!
+//! `rstow` links (stows) every file of a source directory into a target directory. Use a `Stower`
+//! to build a `Plan` of `FSOperation`s, inspect or filter it, then apply it (or dry-run it) to
+//! get a `Report` of errors.
+
+extern crate quicli;
+extern crate im;
+extern crate failure;
+extern crate failure_derive;
+extern crate serde_derive;
+extern crate toml;
+extern crate inotify;
+extern crate ignore;
+extern crate globset;
+extern crate age;
+extern crate sha2;
+extern crate similar;
+extern crate libc;
+
+/// Operations planning to stow a source path
+pub mod stow;
+/// Operations planning to un-stow a source path
+pub mod unstow;
+/// Executors of planned operations
+pub mod interpreters;
+/// Filesystem operations
+pub mod operations;
+/// Apply changes of a source directory as they happen
+pub mod watch;
+/// Lock preventing concurrent runs on a target directory
+pub mod lock;
+/// Comparison of a target directory with its source
+pub mod verify;
+/// Operations dependency graph
+pub mod graph;
+/// Error types
+pub mod errors;
+/// Final execution report
+pub mod report;
+/// Filesystem abstraction
+pub mod filesystem;
+/// Runners of hook commands
+pub mod hooks;
+mod config;
+mod crypto;
+mod alternates;
+mod fileutils;
+mod git;
+mod mapping;
+mod modes;
+mod secrets;
+mod stower;
+mod system_target;
+
+#[cfg(test)]
+#[allow(clippy::redundant_static_lifetimes, clippy::unused_unit)]
+mod test_utils;
+
+pub use im::Vector;
+pub use stower::{Stower, Plan};
+pub use operations::{FSOperation, TraversOperation};
+pub use graph::{OperationGraph, OperationNode};
+pub use errors::{AppError, ErrorPath, ErrorType};
+pub use report::Report;
+pub use alternates::Conditions;
+pub use filesystem::{FileSystem, FileType, MemoryFileSystem, RealFileSystem};
+pub use hooks::{HookRunner, MemoryHookRunner, ShellHookRunner};
@@ -0,0 +1,122 @@
+use quicli::prelude::*;
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::result::Result;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::{AppError, ErrorPath};
+
+/// Lock file created in the target directory
+pub const LOCK_FILE_NAME: &str = ".rstow.lock";
+
+// delay between two lock attempts
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Advisory lock (`flock`) on a target directory held by a rstow run from planning to the end of
+/// apply, released when dropped. The lock file contains the PID of the holding process.
+#[derive(Debug)]
+pub struct TargetLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl TargetLock {
+    /// Lock `target` directory, waiting up to `timeout` if another process holds the lock
+    pub fn acquire<P: AsRef<Path>>(target: P, timeout: Duration) -> Result<TargetLock, AppError> {
+        let path = target.as_ref().join(LOCK_FILE_NAME);
+        let error = |cause: String| AppError::LockError { path: ErrorPath::from(path.as_path()), cause };
+
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path.as_path())
+            .map_err(|e| error(format!("Unable to open lock file ({})", e)))?;
+        let start = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if start.elapsed() < timeout => thread::sleep(RETRY_DELAY),
+                Err(TryLockError::WouldBlock) => {
+                    let mut holder = String::new();
+                    file.read_to_string(&mut holder).ok();
+                    let holder = match holder.trim() {
+                        "" => "another process".to_string(),
+                        pid => format!("process {}", pid),
+                    };
+                    return Err(error(format!("Target is locked by {} (waited {:?})", holder, timeout)));
+                },
+                Err(TryLockError::Error(e)) => return Err(error(format!("Unable to lock ({})", e))),
+            }
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{}", process::id()))
+            .map_err(|e| error(format!("Unable to write lock file ({})", e)))?;
+        debug!("Lock {} acquired", path.display());
+        Ok(TargetLock { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        // the file is kept : removing it would let another process lock a new file
+        self.file.set_len(0).ok();
+        self.file.unlock().ok();
+        debug!("Lock {} released", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod test_lock {
+    use super::*;
+    use stower::Stower;
+    use test_utils::*;
+
+    #[test]
+    fn test_lock() {
+        with_test_directories("test_lock", |_, target| {
+            let lock = TargetLock::acquire(target, Duration::from_millis(0)).unwrap();
+            assert_eq!(lock.path(), target.join(LOCK_FILE_NAME).as_path());
+
+            match TargetLock::acquire(target, Duration::from_millis(200)) {
+                Err(AppError::LockError { cause, .. }) => assert!(cause.contains(&format!("process {}", process::id()))),
+                res => panic!("lock error expected : {:?}", res),
+            }
+
+            drop(lock);
+            assert!(TargetLock::acquire(target, Duration::from_millis(0)).is_ok());
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_plan_lock() {
+        with_test_directories("test_plan_lock", |source, target| {
+            add_file_to("file", source).unwrap();
+            let link = target.with_file_name("target-link");
+            std::os::unix::fs::symlink(target, link.as_path()).unwrap();
+            // the canonical target is locked whatever the path given
+            let stower = Stower::new(source, link.as_path()).lock_timeout(Duration::from_millis(0));
+
+            let plan = stower.plan().unwrap();
+            assert!(target.join(LOCK_FILE_NAME).exists());
+            match Stower::new(source, target).lock_timeout(Duration::from_millis(0)).plan() {
+                Err(AppError::LockError { .. }) => (),
+                res => panic!("lock error expected : {:?}", res),
+            }
+            // filtered plans share the lock
+            let filtered = plan.filter(|_| true);
+            drop(plan);
+            assert!(TargetLock::acquire(target, Duration::from_millis(0)).is_err());
+
+            drop(filtered);
+            assert!(stower.plan().unwrap().apply().is_empty());
+            assert!(target.join("file").symlink_metadata().unwrap().file_type().is_symlink());
+        }).unwrap();
+    }
+}
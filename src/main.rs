@@ -1,40 +1,18 @@
 #![allow(unused)]
 
 #[macro_use] extern crate quicli;
-#[macro_use] extern crate im;
-extern crate failure;
-#[macro_use] extern crate failure_derive;
-#[macro_use] extern crate serde_derive;
-extern crate toml;
+extern crate rstow;
+extern crate libc;
 
 use quicli::prelude::*;
-use im::vector::*;
-use failure::Error;
-use failure::ResultExt;
+use rstow::Stower;
+use rstow::interpreters::{Interpreter, DryRunInterpreter, FileSystemInterpreter, ShellScriptInterpreter};
+use rstow::{AppError, ErrorPath, ErrorType, Plan, RealFileSystem, Report};
 
-use std::result::Result;
-use std::fs::{self};
+use std::ffi::CString;
 use std::path::{Path, PathBuf};
-use std::collections::LinkedList;
-use std::borrow::BorrowMut;
-use std::borrow::Borrow;
-
-mod config;
-mod stow;
-mod unstow;
-mod interpreters;
-mod fileutils;
-mod operations;
-mod errors;
-
-#[cfg(test)]
-mod test_utils;
-
-use fileutils::*;
-use operations::*;
-use errors::*;
-use toml::value::Array;
-use config::RstowConfig;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Like stow but simpler and with more crabs
 #[derive(Debug, StructOpt)]
@@ -53,126 +31,205 @@ struct Cli {
     /// Create a backup of the file before override it with a symlink
     #[structopt(long = "backup", short = "b")]
     backup: bool,
+    /// Allow --force to delete non empty directories on the path of a source file
+    #[structopt(long = "allow-recursive-delete")]
+    allow_recursive_delete: bool,
     /// Dry run rstow (this will do not affect files and logs what should be done)
     #[structopt(long = "dryrun", short = "d")]
     dryrun: bool,
     /// Un-stow a target path from source (will remove symlinks and rename re-use backup files if exist)
     #[structopt(long = "unstow", short = "u")]
     unstow: bool,
+    /// Apply every operation not depending on a conflicting path and report skipped ones
+    #[structopt(long = "keep-going", short = "k")]
+    keep_going: bool,
+    /// Only stow files tracked by git (.gitignore rules are always honoured)
+    #[structopt(long = "git", short = "g")]
+    git: bool,
+    /// Stow source entries prefixed by `dot-` as hidden files (dot-bashrc is linked to .bashrc)
+    #[structopt(long = "dotfiles")]
+    dotfiles: bool,
+    /// Don't ignore built-in patterns (README*, LICENSE*, editor swap files...), ~/.rstow-global-ignore is still used
+    #[structopt(long = "no-default-ignore")]
+    no_default_ignore: bool,
+    /// Class used to choose alternate files like `file##class.work` (can be repeated)
+    #[structopt(long = "class", number_of_values = 1)]
+    classes: Vec<String>,
+    /// Target is shared with other users : refuse to link files looking like secrets
+    #[structopt(long = "shared")]
+    shared: bool,
+    /// age identity file decrypting `*.age` source files into target files
+    #[structopt(long = "identity", short = "i", parse(from_os_str))]
+    identity: Option<PathBuf>,
+    /// User (name or id) owning directories and decrypted files created on target
+    #[structopt(long = "owner")]
+    owner: Option<String>,
+    /// Group (name or id) owning directories and decrypted files created on target
+    #[structopt(long = "group")]
+    group: Option<String>,
+    /// Number of threads used to apply independent operations concurrently
+    #[structopt(long = "jobs", short = "j", default_value = "1")]
+    jobs: usize,
+    /// Seconds to wait for another rstow run holding the target lock (.rstow.lock)
+    #[structopt(long = "lock-timeout", default_value = "10")]
+    lock_timeout: u64,
+    /// Write operations to a POSIX shell script instead of applying them
+    #[structopt(long = "script", parse(from_os_str))]
+    script: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
     // Quick and easy logging setup you get for free with quicli
     #[structopt(flatten)]
     verbosity: Verbosity,
 }
 
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Watch source directory and stow created files (or un-stow removed ones) as they change
+    #[structopt(name = "watch")]
+    Watch {
+        /// Delay in milliseconds without change before applying changes
+        #[structopt(long = "debounce", default_value = "500")]
+        debounce: u64,
+    },
+    /// Compare target with source : missing or modified copies, files of linked directories not in source
+    #[structopt(name = "verify")]
+    Verify,
+}
 
 main!(|args: Cli, log_level: verbosity| {
-   program(&args);
+   std::process::exit(program(&args));
 });
 
 /// Program execution follow 3 steps :
 /// 1- Extract and verify inputs (provided `source` and `target` became absolute paths)
 /// 2- Traverse recursively (if directory) `source` and build paths relative to `target` to find what operation
 /// should be done to stow or unstow `source` to `target`
-/// 3- Apply operations using an interpreter (`dryrun` or `filesystem` depending of `dryrun` flag state)
-fn program(args: &Cli) {
-    let dryrun = &args.dryrun;
-    let force = &args.force;
-    let backup = &args.backup;
-    let unstow = &args.unstow;
-
-    let source = fs::canonicalize(&args.source).expect("Unresolved absolute source path");
-    let target = fs::canonicalize(&args.target).expect("Unresolved absolute target path");
-
-    info!("Stow from Source {:?} to target {:?}", source.display(), target.display());
-
-    let mut operations: Vector<Result<FSOperation, AppError>> = Vector::new();
-    traverse_fs(source.as_path(), target.as_path(), *force, *backup, *unstow, &mut operations).expect("An error occurred when traversing directories");
-    apply(operations.borrow(), *dryrun).unwrap_or_else(|e| {
-        error!("{}", e);
-    })
-}
-
-
-fn traverse_fs(source: &Path, target: &Path, force: bool, backup: bool, unstow: bool, operations: &mut Vector<Result<FSOperation, AppError>>) -> Result<(), AppError> {
-
-    if source.is_dir() {
-        let config = config::read_config_file(source).unwrap_or(RstowConfig::default());
-        let source_paths = fs::read_dir(source)?;
-
-        for src_dir_entry in source_paths {
-            let path = src_dir_entry?.path();
-            let file_name = path.as_path().file_name().expect("Unable to get path filename");
-
-            if RstowConfig::is_ignored(&config, file_name.to_str().unwrap_or("")) {
-                debug!("File {} ignored", path.as_path().display());
-            } else {
-                let target_file_path = target.join(file_name);
+/// 3- Run operations with an `Interpreter` (`DryRunInterpreter`, `FileSystemInterpreter` or `ShellScriptInterpreter` depending of flags)
+/// and report errors. Returned value is the process exit code.
+fn program(args: &Cli) -> i32 {
+    let fs = Arc::new(RealFileSystem);
+    let interpreter: Box<dyn Interpreter> = if args.dryrun {
+        Box::new(DryRunInterpreter::new(fs.clone()))
+    } else {
+        Box::new(FileSystemInterpreter::new(fs.clone()))
+    };
 
-                let travers_result = visit_node(path.as_path(), target_file_path.as_path(), force, backup, unstow, operations.borrow_mut());
-                match travers_result {
-                    Ok(TraversOperation::StopPathRun) => (),
-                    Ok(TraversOperation::Continue) => {
-                        if path.as_path().is_dir() {
-                            traverse_fs(path.as_path(), target_file_path.as_path(), force, backup, unstow, operations)?;
-                        }
-                    },
-                    Err(e) => error!("{}", e),
+    let mut stower = Stower::new(&args.source, &args.target)
+        .force(args.force)
+        .backup(args.backup)
+        .allow_recursive_delete(args.allow_recursive_delete)
+        .unstow(args.unstow)
+        .jobs(args.jobs)
+        .keep_going(args.keep_going)
+        .git(args.git)
+        .dotfiles(args.dotfiles)
+        .default_ignore(!args.no_default_ignore)
+        .shared(args.shared)
+        .filesystem(fs.clone());
+    for (name, is_user) in [(&args.owner, true), (&args.group, false)] {
+        if let Some(name) = name {
+            match resolve_id(name, is_user) {
+                Some(id) if is_user => stower = stower.owner(id),
+                Some(id) => stower = stower.group(id),
+                None => {
+                    eprintln!("Unknown {} {}", if is_user { "user" } else { "group" }, name);
+                    return ErrorType::Config.exit_code();
                 }
             }
         }
-    } else {
-        visit_node(source, target, force, backup, unstow, operations.borrow_mut());
     }
-    Ok(())
-}
+    if let Some(identity) = &args.identity {
+        stower = stower.identity(identity);
+    }
+    for class in args.classes.iter() {
+        stower = stower.class(class.as_str());
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        stower = stower.global_ignore(PathBuf::from(home).join(".rstow-global-ignore"));
+    }
+    // a dry-run or a script export doesn't change target
+    let watching = matches!(args.command, Some(Command::Watch { .. }));
+    if watching || !(args.dryrun || args.script.is_some()) {
+        stower = stower.lock_timeout(Duration::from_secs(args.lock_timeout));
+    }
+
+    if let Some(Command::Watch { debounce }) = args.command {
+        let mut report = Report::new();
+        if let Err(e) = rstow::watch::watch(&stower, Duration::from_millis(debounce)) {
+            report.add(e);
+        }
+        report.print();
+        return report.exit_code();
+    }
 
-fn visit_node(source: &Path, target: &Path, force: bool, backup: bool, unstow: bool, operations: &mut Vector<Result<FSOperation, AppError>>) -> Result<TraversOperation, AppError> {
+    if let Some(Command::Verify) = args.command {
+        return verify(&stower);
+    }
 
-    let mut node_operations: Vector<FSOperation> = Vector::new();
-    let travers_result = {
-        if unstow {
-            unstow::unstow_path(source, target, node_operations.borrow_mut())
-        } else {
-            stow::stow_path(source, target, force, backup, node_operations.borrow_mut())
+    let report = match stower.plan() {
+        Ok(plan) => match &args.script {
+            Some(path) => export_script(&plan, fs, path.as_path()),
+            None => plan.run(interpreter.as_ref()),
+        },
+        Err(e) => {
+            let mut report = Report::new();
+            report.add(e);
+            report
         }
     };
+    report.print();
+    report.exit_code()
+}
 
-    match travers_result {
-        Ok(travers_op) => {
-            for op in node_operations {
-                operations.push_back(Ok(op));
+/// Print drifts of target from source (with a diff of modified copies) and return the exit code
+fn verify(stower: &Stower) -> i32 {
+    let report = match stower.verify() {
+        Ok(verification) => {
+            for drift in verification.drifts() {
+                println!("{} : {}", drift.target.display(), drift.cause);
+                if let Some(diff) = &drift.diff {
+                    print!("{}", diff);
+                }
             }
-            Ok(travers_op)
+            verification.report()
         },
         Err(e) => {
-            operations.push_back(Err(e));
-            Ok(TraversOperation::Continue)
-        },
-    }
+            let mut report = Report::new();
+            report.add(e);
+            report
+        }
+    };
+    report.print();
+    report.exit_code()
 }
 
-fn apply(operations: &Vector<Result<FSOperation, AppError>>, dryrun: bool) -> Result<(), AppError> {
-    if dryrun {
-        interpreters::dryrun_interpreter(operations.borrow())
-    } else {
-
-        let mut operations_valid: Vector<&FSOperation> = Vector::new();
-        let mut operations_error: Vector<&AppError> = Vector::new();
-
-        for res_op in operations.iter() {
-            match res_op {
-                Ok(op) => operations_valid.push_back(op),
-                Err(err) => operations_error.push_back(err)
-            }
-        };
-
-        if !operations_error.is_empty() {
-            for err in operations_error.iter() {
-                error!("{}", err)
-            }
-            Err(AppError::ApplyError)
+/// Id of a user (or group) name or numeric id, looked up with the system user database (NSS)
+fn resolve_id(name: &str, is_user: bool) -> Option<u32> {
+    if let Ok(id) = name.parse() {
+        return Some(id);
+    }
+    let name = CString::new(name).ok()?;
+    // the returned entries point to static storage, read before any other lookup
+    unsafe {
+        if is_user {
+            let passwd = libc::getpwnam(name.as_ptr());
+            if passwd.is_null() { None } else { Some((*passwd).pw_uid) }
         } else {
-            interpreters::filesystem_interpreter(operations_valid.borrow())
+            let group = libc::getgrnam(name.as_ptr());
+            if group.is_null() { None } else { Some((*group).gr_gid) }
+        }
+    }
+}
+
+/// Write the plan as a shell script to `path`, only if the plan has no error
+fn export_script(plan: &Plan, fs: Arc<RealFileSystem>, path: &Path) -> Report {
+    let interpreter = ShellScriptInterpreter::new(fs);
+    let mut report = plan.run(&interpreter);
+    if report.is_empty() {
+        if let Err(e) = std::fs::write(path, interpreter.script()) {
+            report.add(AppError::IOError { path: ErrorPath::from(path), cause: e.to_string() });
         }
     }
+    report
 }
@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use config::{RstowConfig, SourceConfigs};
+use errors::AppError;
+use fileutils::relative_path;
+
+/// Target path given to a source `path` by a `[map]` rule of the `.rstow` file of a parent
+/// directory, relative paths being resolved against the `target` directory
+pub(crate) fn mapped_target(configs: &SourceConfigs, target: &Path, path: &Path) -> Result<Option<PathBuf>, AppError> {
+    for directory in configs.parents(path) {
+        let config = configs.get(directory)?;
+        if let Some(mapped) = RstowConfig::mapped_path(&config, relative_path(path, directory)?) {
+            return Ok(Some(target.join(mapped)));
+        }
+    }
+    Ok(None)
+}
+
+/// A directory with mapped children is created on target instead of being linked as a whole
+pub(crate) fn has_mapped_children(configs: &SourceConfigs, directory: &Path) -> Result<bool, AppError> {
+    for ancestor in Some(directory).into_iter().chain(configs.parents(directory)) {
+        let config = configs.get(ancestor)?;
+        if config.map.keys().map(|source| ancestor.join(source)).any(|p| p != directory && p.starts_with(directory)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test_mapping {
+    use super::*;
+    use filesystem::{FileSystem, MemoryFileSystem};
+    use operations::FSOperation;
+    use stower::Stower;
+    use std::sync::Arc;
+
+    fn map_source() -> Arc<MemoryFileSystem> {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/vscode/settings.json", b"{}").unwrap();
+        fs.add_file("/source/vscode/keybindings.json", b"[]").unwrap();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[map]\n\"vscode/settings.json\" = \".config/Code/User/settings.json\"\n\"vscode/sub/absolute\" = \"/etc/absolute\"").unwrap();
+        fs.add_dir("/target").unwrap();
+        Arc::new(fs)
+    }
+
+    #[test]
+    fn test_mapped_target() {
+        let fs = map_source();
+        let configs = SourceConfigs::new(fs.as_ref(), Path::new("/source"));
+        let target = Path::new("/target");
+
+        assert_eq!(mapped_target(&configs, target, Path::new("/source/vscode/settings.json")).unwrap(), Some(PathBuf::from("/target/.config/Code/User/settings.json")));
+        assert_eq!(mapped_target(&configs, target, Path::new("/source/vscode/sub/absolute")).unwrap(), Some(PathBuf::from("/etc/absolute")));
+        assert_eq!(mapped_target(&configs, target, Path::new("/source/vscode/keybindings.json")).unwrap(), None);
+
+        assert!(has_mapped_children(&configs, Path::new("/source/vscode")).unwrap());
+        assert!(has_mapped_children(&configs, Path::new("/source/vscode/sub")).unwrap());
+        assert!(!has_mapped_children(&configs, Path::new("/source/vscode/settings.json")).unwrap());
+    }
+
+    #[test]
+    fn test_map_rules() {
+        let fs = map_source();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+
+        let plan = stower.plan().unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        // directory with mapped children is created instead of being linked as a whole
+        assert_eq!(operations, vec![
+            FSOperation::CreateDir(PathBuf::from("/target/vscode")),
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/vscode/keybindings.json"), target: PathBuf::from("/target/vscode/keybindings.json") },
+            FSOperation::CreateDir(PathBuf::from("/target/.config/Code/User")),
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/vscode/settings.json"), target: PathBuf::from("/target/.config/Code/User/settings.json") },
+        ]);
+        assert!(plan.apply().is_empty());
+
+        let plan = stower.unstow(true).plan().unwrap();
+        assert!(plan.operations().any(|op| op.as_ref().ok() == Some(&FSOperation::DeleteSymlink(PathBuf::from("/target/.config/Code/User/settings.json")))));
+        assert!(plan.apply().is_empty());
+        assert!(!fs.exists(Path::new("/target/.config/Code/User/settings.json")));
+        assert!(!fs.exists(Path::new("/target/vscode/keybindings.json")));
+    }
+}
@@ -0,0 +1,164 @@
+use quicli::prelude::*;
+use globset::GlobBuilder;
+
+use std::path::Path;
+use std::result::Result;
+
+use config::{RstowConfig, SourceConfigs};
+use crypto;
+use errors::{AppError, ErrorPath};
+use fileutils::{file_name_str, relative_path};
+
+/// Modes expected by programs reading usual private files, for target paths relative to the
+/// target directory (`*` doesn't match `/`). A linked source more open than this is warned.
+pub(crate) const PRIVATE_TARGET_MODES: &[(&str, u32)] = &[
+    (".ssh/*", 0o600),
+    (".gnupg/*", 0o600),
+    (".netrc", 0o600),
+    (".pgpass", 0o600),
+];
+
+/// Mode expected for a target path relative to the target directory, `None` if it isn't a
+/// usual private file
+pub(crate) fn private_target_mode(relative: &Path) -> Option<u32> {
+    PRIVATE_TARGET_MODES.iter()
+        .find(|(pattern, _)| GlobBuilder::new(pattern).literal_separator(true).build()
+            .map(|g| g.compile_matcher().is_match(relative))
+            .unwrap_or(false))
+        .map(|(_, mode)| *mode)
+}
+
+/// Mode required for a source path by a `[modes]` rule of the `.rstow` file of the nearest
+/// parent directory having one
+pub(crate) fn required_mode(configs: &SourceConfigs, path: &Path) -> Result<Option<u32>, AppError> {
+    for directory in configs.parents(path) {
+        let config = configs.get(directory)?;
+        if let Some(mode) = RstowConfig::mode(&config, relative_path(path, directory)?) {
+            return Ok(Some(mode));
+        }
+    }
+    Ok(None)
+}
+
+/// A source path more open than its required mode is an error (on its `target` path, below the
+/// `target_root` directory). A source file without rule is warned if writable by others, or if
+/// more open (group-writable included) than the usual mode of a private target file like
+/// `.ssh/config`. Group-writable sources of a system target are errors, see `system_target`.
+pub(crate) fn mode_error(configs: &SourceConfigs, target_root: &Path, source: &Path, target: &Path) -> Result<Option<AppError>, AppError> {
+    let fs = configs.fs();
+    let mode = match fs.mode(source) {
+        Ok(mode) => mode,
+        // dangling links are reported by stow
+        Err(_) => return Ok(None),
+    };
+    match required_mode(configs, source)? {
+        Some(required) if mode & 0o777 & !required != 0 => {
+            return Ok(Some(AppError::ModeError {
+                source: ErrorPath::from(source),
+                target: ErrorPath::from(target),
+                cause: format!("mode {:04o} is more open than required {:04o}", mode, required)
+            }));
+        },
+        // group-writable files are common with a 002 umask
+        None if !fs.is_dir(source) && mode & 0o002 != 0 => {
+            warn!("Source {} is writable by others (mode {:04o})", source.display(), mode);
+        },
+        None if !fs.is_dir(source) && crypto::decrypted_name(file_name_str(source)?).is_none() => {
+            let private_mode = target.strip_prefix(target_root).ok().and_then(private_target_mode);
+            match private_mode {
+                Some(private_mode) if mode & 0o777 & !private_mode != 0 => {
+                    warn!("Source {} linked to {} is more open (mode {:04o}) than expected for this file ({:04o})",
+                        source.display(), target.display(), mode, private_mode);
+                },
+                _ => (),
+            }
+        },
+        _ => (),
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test_modes {
+    use super::*;
+    use filesystem::{FileSystem, MemoryFileSystem};
+    use operations::FSOperation;
+    use stower::Stower;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn ssh_source() -> Arc<MemoryFileSystem> {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/.ssh/config", b"Host *").unwrap();
+        fs.add_file("/source/.ssh/.rstow", b"symlink_current_dir = false\nignore_files = []").unwrap();
+        fs.add_file("/source/.rstow", b"symlink_current_dir = true\nignore_files = []\n[modes]\n\".ssh\" = \"0700\"\n\".ssh/*\" = \"0600\"").unwrap();
+        fs.set_mode(Path::new("/source/.ssh"), 0o700).unwrap();
+        fs.add_dir("/target").unwrap();
+        Arc::new(fs)
+    }
+
+    #[test]
+    fn test_private_target_mode() {
+        assert_eq!(private_target_mode(Path::new(".ssh/config")), Some(0o600));
+        assert_eq!(private_target_mode(Path::new(".netrc")), Some(0o600));
+        assert_eq!(private_target_mode(Path::new(".ssh/keys/id")), None);
+        assert_eq!(private_target_mode(Path::new(".bashrc")), None);
+    }
+
+    #[test]
+    fn test_mode_error() {
+        let fs = ssh_source();
+        fs.add_file("/source/notes.txt", b"").unwrap();
+        let configs = SourceConfigs::new(fs.as_ref(), Path::new("/source"));
+        let target = Path::new("/target");
+
+        assert_eq!(required_mode(&configs, Path::new("/source/.ssh")).unwrap(), Some(0o700));
+        assert_eq!(required_mode(&configs, Path::new("/source/.ssh/config")).unwrap(), Some(0o600));
+        assert_eq!(required_mode(&configs, Path::new("/source/notes.txt")).unwrap(), None);
+
+        match mode_error(&configs, target, Path::new("/source/.ssh/config"), Path::new("/target/.ssh/config")).unwrap() {
+            Some(AppError::ModeError { source, target, cause }) => {
+                assert_eq!(source.as_path(), Path::new("/source/.ssh/config"));
+                assert_eq!(target.as_path(), Path::new("/target/.ssh/config"));
+                assert_eq!(cause, "mode 0644 is more open than required 0600");
+            },
+            res => panic!("mode error expected : {:?}", res),
+        }
+        fs.set_mode(Path::new("/source/.ssh/config"), 0o600).unwrap();
+        assert!(mode_error(&configs, target, Path::new("/source/.ssh/config"), Path::new("/target/.ssh/config")).unwrap().is_none());
+        // files without rule are only warned
+        fs.set_mode(Path::new("/source/notes.txt"), 0o666).unwrap();
+        assert!(mode_error(&configs, target, Path::new("/source/notes.txt"), Path::new("/target/.ssh/notes.txt")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_modes() {
+        let fs = ssh_source();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+
+        // the mode error is reported on the target link, which depends on it
+        let plan = stower.plan().unwrap();
+        let errors: Vec<&Path> = plan.errors().filter_map(|e| e.path()).collect();
+        assert_eq!(errors, vec![Path::new("/target/.ssh/config")]);
+        assert!(!plan.apply().is_empty());
+        assert!(!fs.exists(Path::new("/target/.ssh")));
+
+        let report = stower.clone().keep_going(true).plan().unwrap().apply();
+        assert_eq!(report.errors().len(), 1);
+        assert!(fs.is_dir(Path::new("/target/.ssh")));
+        assert!(!fs.is_symlink(Path::new("/target/.ssh/config")));
+        fs.remove_dir_all(Path::new("/target/.ssh")).unwrap();
+
+        // created directory gets its required mode
+        fs.set_mode(Path::new("/source/.ssh/config"), 0o600).unwrap();
+        let plan = stower.plan().unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![
+            FSOperation::CreateDir(PathBuf::from("/target/.ssh")),
+            FSOperation::SetMode { path: PathBuf::from("/target/.ssh"), mode: 0o700 },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/.ssh/config"), target: PathBuf::from("/target/.ssh/config") },
+        ]);
+        assert!(plan.apply().is_empty());
+        assert_eq!(fs.mode(Path::new("/target/.ssh")).unwrap(), 0o700);
+    }
+}
@@ -1,24 +1,196 @@
 
 use std::path::{Path, PathBuf};
 use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
 
+use fileutils::build_backup_path;
+
+/// Tells if source traversal should visit children of a directory
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum TraversOperation {
+pub enum TraversOperation {
+    /// Visit children
     Continue,
+    /// Don't visit children (e.g. the whole directory is linked)
     StopPathRun
 }
 
+/// Filesystem operation planned on a target path
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum FSOperation {
+pub enum FSOperation {
+    /// Rename a path with a `.backup` suffix
     Backup(PathBuf),
+    /// Rename a backup to its original path
     Restore { backup: PathBuf, target: PathBuf },
+    /// Create a `target` symbolic link to `source`
     CreateSymlink { source: PathBuf, target: PathBuf },
+    /// Replace the `target` symbolic link by a link to `source` : the new link is created under a
+    /// temporary name then renamed over the old one, so `target` never disappears
+    ReplaceSymlink { source: PathBuf, target: PathBuf },
+    /// Create a directory (and its parents)
     CreateDir(PathBuf),
-    Delete(PathBuf),
+    /// Delete a symbolic link (never what it points to)
+    DeleteSymlink(PathBuf),
+    /// Delete a regular file
+    DeleteFile(PathBuf),
+    /// Delete an empty directory
+    DeleteEmptyDir(PathBuf),
+    /// Delete a real directory and its content, only planned with `--allow-recursive-delete`
+    DeleteDirRecursive(PathBuf),
+    /// Replace a directory symbolic link by a real directory containing links to the previous directory children
     BreakDirectoryLink(PathBuf) ,
+    /// Decrypt an encrypted `source` file into a real `target` file (mode `0600`) with an
+    /// age `identity` file
+    Decrypt { source: PathBuf, target: PathBuf, identity: PathBuf },
+    /// Overwrite a file with zeros then delete it
+    SecureDelete(PathBuf),
+    /// Change user and/or group owning a path
+    SetOwner { path: PathBuf, uid: Option<u32>, gid: Option<u32> },
+    /// Change permission bits of a path
+    SetMode { path: PathBuf, mode: u32 },
+    /// Run a `hook` command of the `source` package (`pre_stow`, `post_stow`...) with the
+    /// target paths `changed` by the plan
+    RunHook { hook: String, command: String, source: PathBuf, target: PathBuf, changed: Vec<PathBuf> },
+    /// Nothing to do on a path
     Nothing{path: PathBuf, cause: String},
 }
 
+impl FSOperation {
+    /// Paths that must exist before applying the operation
+    pub fn requires(&self) -> Vec<PathBuf> {
+        match self {
+            FSOperation::Backup(p) => vec![p.to_path_buf()],
+            FSOperation::Restore { backup, target } => with_parent(target, vec![backup.to_path_buf()]),
+            FSOperation::CreateSymlink { source, target } => with_parent(target, vec![source.to_path_buf()]),
+            FSOperation::ReplaceSymlink { source, target } => with_parent(target, vec![source.to_path_buf(), target.to_path_buf()]),
+            FSOperation::CreateDir(_) => Vec::new(),
+            FSOperation::DeleteSymlink(p) | FSOperation::DeleteFile(p) | FSOperation::DeleteEmptyDir(p) | FSOperation::DeleteDirRecursive(p) => vec![p.to_path_buf()],
+            FSOperation::BreakDirectoryLink(p) => vec![p.to_path_buf()],
+            FSOperation::RunHook { source, .. } => vec![source.to_path_buf()],
+            FSOperation::SetMode { path, .. } => vec![path.to_path_buf()],
+            FSOperation::SetOwner { path, .. } => vec![path.to_path_buf()],
+            FSOperation::Decrypt { source, target, .. } => with_parent(target, vec![source.to_path_buf()]),
+            FSOperation::SecureDelete(p) => vec![p.to_path_buf()],
+            FSOperation::Nothing { .. } => Vec::new(),
+        }
+    }
+
+    /// Paths created by the operation
+    pub fn produces(&self) -> Vec<PathBuf> {
+        match self {
+            FSOperation::Backup(p) => build_backup_path(p).into_iter().collect(),
+            FSOperation::Restore { target, .. } => vec![target.to_path_buf()],
+            FSOperation::CreateSymlink { target, .. } => vec![target.to_path_buf()],
+            FSOperation::ReplaceSymlink { target, .. } => vec![target.to_path_buf()],
+            FSOperation::CreateDir(p) => vec![p.to_path_buf()],
+            FSOperation::DeleteSymlink(_) | FSOperation::DeleteFile(_) | FSOperation::DeleteEmptyDir(_) | FSOperation::DeleteDirRecursive(_) => Vec::new(),
+            FSOperation::BreakDirectoryLink(p) => vec![p.to_path_buf()],
+            FSOperation::RunHook { .. } => Vec::new(),
+            FSOperation::SetMode { .. } => Vec::new(),
+            FSOperation::SetOwner { .. } => Vec::new(),
+            FSOperation::Decrypt { target, .. } => vec![target.to_path_buf()],
+            FSOperation::SecureDelete(_) => Vec::new(),
+            FSOperation::Nothing { .. } => Vec::new(),
+        }
+    }
+
+    /// Paths removed by the operation
+    pub fn removes(&self) -> Vec<PathBuf> {
+        match self {
+            FSOperation::Backup(p) => vec![p.to_path_buf()],
+            FSOperation::Restore { backup, .. } => vec![backup.to_path_buf()],
+            FSOperation::CreateSymlink { .. } => Vec::new(),
+            FSOperation::ReplaceSymlink { target, .. } => vec![target.to_path_buf()],
+            FSOperation::CreateDir(_) => Vec::new(),
+            FSOperation::DeleteSymlink(p) | FSOperation::DeleteFile(p) | FSOperation::DeleteEmptyDir(p) | FSOperation::DeleteDirRecursive(p) => vec![p.to_path_buf()],
+            FSOperation::BreakDirectoryLink(p) => vec![p.to_path_buf()],
+            FSOperation::RunHook { .. } => Vec::new(),
+            FSOperation::SetMode { .. } => Vec::new(),
+            FSOperation::SetOwner { .. } => Vec::new(),
+            FSOperation::Decrypt { .. } => Vec::new(),
+            FSOperation::SecureDelete(p) => vec![p.to_path_buf()],
+            FSOperation::Nothing { .. } => Vec::new(),
+        }
+    }
+
+    /// Paths modified in place by the operation : a hook may change anything in its target
+    /// directory, so it is ordered after previous and before following operations on it
+    pub fn modifies(&self) -> Vec<PathBuf> {
+        match self {
+            FSOperation::RunHook { target, .. } => vec![target.to_path_buf()],
+            FSOperation::SetMode { path, .. } => vec![path.to_path_buf()],
+            FSOperation::SetOwner { path, .. } => vec![path.to_path_buf()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Source and target of the symbolic link created by the operation (`CreateSymlink` or
+    /// `ReplaceSymlink`)
+    pub fn link(&self) -> Option<(&Path, &Path)> {
+        match self {
+            FSOperation::CreateSymlink { source, target } | FSOperation::ReplaceSymlink { source, target } => Some((source, target)),
+            _ => None,
+        }
+    }
+
+    /// Operations reverting this one once applied, `None` when it can't be undone (deleted or
+    /// overwritten content is lost, a hook may have changed anything)
+    pub fn undo(&self) -> Option<Vec<FSOperation>> {
+        match self {
+            FSOperation::Backup(p) => build_backup_path(p).ok()
+                .map(|backup| vec![FSOperation::Restore { backup, target: p.to_path_buf() }]),
+            FSOperation::Restore { backup, target } => match build_backup_path(target) {
+                Ok(ref path) if path == backup => Some(vec![FSOperation::Backup(target.to_path_buf())]),
+                _ => None,
+            },
+            FSOperation::CreateSymlink { target, .. } => Some(vec![FSOperation::DeleteSymlink(target.to_path_buf())]),
+            FSOperation::CreateDir(p) => Some(vec![FSOperation::DeleteEmptyDir(p.to_path_buf())]),
+            FSOperation::Decrypt { target, .. } => Some(vec![FSOperation::SecureDelete(target.to_path_buf())]),
+            FSOperation::Nothing { .. } => Some(Vec::new()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FSOperation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FSOperation::Nothing{path, cause} => write!(f, "nothing to do on {} ({})", path.display(), cause),
+            FSOperation::Backup(p) => write!(f, "backup {}", p.display()),
+            FSOperation::CreateDir(p) => write!(f, "create directory {}", p.display()),
+            FSOperation::Restore {backup, target} => write!(f, "restore {} -> {}", backup.display(), target.display()),
+            FSOperation::BreakDirectoryLink(p) => write!(f, "break directory link {} and rebuild children links", p.display()),
+            FSOperation::DeleteSymlink(p) => write!(f, "delete symbolic link {}", p.display()),
+            FSOperation::DeleteFile(p) => write!(f, "delete file {}", p.display()),
+            FSOperation::DeleteEmptyDir(p) => write!(f, "delete empty directory {}", p.display()),
+            FSOperation::DeleteDirRecursive(p) => write!(f, "delete directory recursively {}", p.display()),
+            FSOperation::CreateSymlink{source, target} => write!(f, "create symbolic link {} -> {}", source.display(), target.display()),
+            FSOperation::ReplaceSymlink{source, target} => write!(f, "replace symbolic link {} -> {}", source.display(), target.display()),
+            FSOperation::RunHook{hook, command, source, ..} => write!(f, "run {} hook `{}` in {}", hook, command, source.display()),
+            FSOperation::SetMode{path, mode} => write!(f, "set mode {:04o} on {}", mode, path.display()),
+            FSOperation::SetOwner{path, uid, gid} => write!(f, "set owner {} on {}", owner_spec(*uid, *gid), path.display()),
+            FSOperation::Decrypt{source, target, ..} => write!(f, "decrypt {} -> {}", source.display(), target.display()),
+            FSOperation::SecureDelete(p) => write!(f, "securely delete {}", p.display()),
+        }
+    }
+}
+
+/// `uid:gid` like `chown` expects, an unchanged id being omitted
+pub(crate) fn owner_spec(uid: Option<u32>, gid: Option<u32>) -> String {
+    match (uid, gid) {
+        (Some(uid), Some(gid)) => format!("{}:{}", uid, gid),
+        (Some(uid), None) => uid.to_string(),
+        (None, Some(gid)) => format!(":{}", gid),
+        (None, None) => String::new(),
+    }
+}
+
+fn with_parent(path: &Path, mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    if let Some(parent) = path.parent() {
+        paths.push(parent.to_path_buf());
+    }
+    paths
+}
+
 #[test]
 fn test_fsoperation_equals() {
     //test Backup
@@ -28,7 +200,8 @@ fn test_fsoperation_equals() {
     assert_eq!(FSOperation::CreateDir(PathBuf::from("/some/path")), FSOperation::CreateDir(PathBuf::from("/some/path")));
 
     //test Delete
-    assert_eq!(FSOperation::Delete(PathBuf::from("/some/path")), FSOperation::Delete(PathBuf::from("/some/path")));
+    assert_eq!(FSOperation::DeleteFile(PathBuf::from("/some/path")), FSOperation::DeleteFile(PathBuf::from("/some/path")));
+    assert_ne!(FSOperation::DeleteFile(PathBuf::from("/some/path")), FSOperation::DeleteSymlink(PathBuf::from("/some/path")));
 
     //test Restore
     assert_eq!(FSOperation::Restore { backup: PathBuf::from("/some/path1"), target: PathBuf::from("/target/path1") },
@@ -48,7 +221,7 @@ fn test_fsoperation_backup_not_equals() {
 #[test]
 #[should_panic]
 fn test_fsoperation_delete_not_equals() {
-    assert_eq!(FSOperation::Delete(PathBuf::from("/some/path")), FSOperation::Delete(PathBuf::from("/other/path")))
+    assert_eq!(FSOperation::DeleteFile(PathBuf::from("/some/path")), FSOperation::DeleteFile(PathBuf::from("/other/path")))
 }
 
 #[test]
@@ -65,6 +238,32 @@ fn test_fsoperation_symlink_not_equals() {
                FSOperation::CreateSymlink { source: PathBuf::from("/different/source/path1"), target: PathBuf::from("/target/path1") });
 }
 
+#[test]
+fn test_fsoperation_paths() {
+    let operation = FSOperation::CreateSymlink { source: PathBuf::from("/source/file"), target: PathBuf::from("/target/file") };
+    assert_eq!(operation.requires(), vec![PathBuf::from("/source/file"), PathBuf::from("/target")]);
+    assert_eq!(operation.produces(), vec![PathBuf::from("/target/file")]);
+    assert!(operation.removes().is_empty());
+
+    let operation = FSOperation::Backup(PathBuf::from("/target/file"));
+    assert_eq!(operation.produces(), vec![PathBuf::from("/target/file.backup")]);
+    assert_eq!(operation.removes(), vec![PathBuf::from("/target/file")]);
+}
+
+#[test]
+fn test_fsoperation_undo() {
+    let operation = FSOperation::CreateSymlink { source: PathBuf::from("/source/file"), target: PathBuf::from("/target/file") };
+    assert_eq!(operation.undo(), Some(vec![FSOperation::DeleteSymlink(PathBuf::from("/target/file"))]));
+
+    let operation = FSOperation::Backup(PathBuf::from("/target/file"));
+    let restore = FSOperation::Restore { backup: PathBuf::from("/target/file.backup"), target: PathBuf::from("/target/file") };
+    assert_eq!(operation.undo(), Some(vec![restore.clone()]));
+    assert_eq!(restore.undo(), Some(vec![operation]));
+
+    assert_eq!(FSOperation::DeleteFile(PathBuf::from("/target/file")).undo(), None);
+    assert_eq!(FSOperation::SetMode { path: PathBuf::from("/target/file"), mode: 0o600 }.undo(), None);
+}
+
 #[test]
 fn test_fsoperation_clone() {
     let operation = FSOperation::Backup(PathBuf::from("/some/path"));
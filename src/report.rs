@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use errors::*;
+use operations::FSOperation;
+
+/// Final report of a rstow execution listing every error with the suggested fix
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    errors: Vec<AppError>,
+    skipped: Vec<FSOperation>,
+    rolled_back: Vec<FSOperation>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    pub fn add(&mut self, error: AppError) {
+        self.errors.push(error);
+    }
+
+    pub fn add_all<I: IntoIterator<Item = AppError>>(&mut self, errors: I) {
+        for error in errors {
+            self.add(error);
+        }
+    }
+
+    /// Add operations not applied because they depend on a failed path
+    pub fn add_skipped<I: IntoIterator<Item = FSOperation>>(&mut self, operations: I) {
+        self.skipped.extend(operations);
+    }
+
+    pub fn skipped(&self) -> &[FSOperation] {
+        &self.skipped
+    }
+
+    /// Add applied operations undone after a failure
+    pub fn add_rolled_back<I: IntoIterator<Item = FSOperation>>(&mut self, operations: I) {
+        self.rolled_back.extend(operations);
+    }
+
+    pub fn rolled_back(&self) -> &[FSOperation] {
+        &self.rolled_back
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn errors(&self) -> &[AppError] {
+        &self.errors
+    }
+
+    /// Number of errors for each error type
+    pub fn summary(&self) -> BTreeMap<ErrorType, usize> {
+        let mut summary: BTreeMap<ErrorType, usize> = BTreeMap::new();
+        for error in self.errors.iter() {
+            *summary.entry(error.error_type()).or_insert(0) += 1;
+        }
+        summary
+    }
+
+    /// Process exit code : 0 on success, otherwise the code of the most severe error type
+    /// (1 for conflicts, 2 for IO errors and 3 for configuration errors)
+    pub fn exit_code(&self) -> i32 {
+        self.errors.iter()
+            .map(|e| e.error_type())
+            .max()
+            .map(|t| t.exit_code())
+            .unwrap_or(0)
+    }
+
+    pub fn print(&self) {
+        if !self.skipped.is_empty() {
+            eprintln!("Skipped operations :");
+            for op in self.skipped.iter() {
+                eprintln!("  - {}", op);
+            }
+        }
+
+        if !self.rolled_back.is_empty() {
+            eprintln!("Rolled back operations :");
+            for op in self.rolled_back.iter() {
+                eprintln!("  - {}", op);
+            }
+        }
+
+        if self.is_empty() {
+            return;
+        }
+
+        eprintln!("Errors :");
+        for error in self.errors.iter() {
+            match error.path() {
+                Some(path) => eprintln!("  - {} : {}", path.display(), error.reason()),
+                None => eprintln!("  - {}", error.reason()),
+            }
+            if let Some(suggestion) = error.suggestion() {
+                eprintln!("      suggestion : {}", suggestion);
+            }
+        }
+
+        let summary: Vec<String> = self.summary().iter()
+            .map(|(error_type, count)| format!("{} {}", count, error_type.label()))
+            .collect();
+        eprintln!("Summary : {}", summary.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod test_report {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn conflict(target: &str) -> AppError {
+        AppError::StowPathError {
+            source: ErrorPath::from(PathBuf::from("/source/file")),
+            target: ErrorPath::from(PathBuf::from(target)),
+            cause: "Target file already physically exist".to_string(),
+            hint: FORCE_HINT
+        }
+    }
+
+    #[test]
+    fn test_empty_report() {
+        let report = Report::new();
+        assert!(report.is_empty());
+        assert_eq!(report.exit_code(), 0);
+    }
+
+    #[test]
+    fn test_report_summary() {
+        let mut report = Report::new();
+        report.add(conflict("/target/file1"));
+        report.add(conflict("/target/file2"));
+
+        assert_eq!(report.errors().len(), 2);
+        assert_eq!(report.summary().get(&ErrorType::Conflict), Some(&2));
+        assert_eq!(report.exit_code(), 1);
+
+        report.add(AppError::IOError { path: ErrorPath::from(PathBuf::from("/target/file3")), cause: "Permission denied".to_string() });
+        assert_eq!(report.errors()[2].path(), Some(PathBuf::from("/target/file3").as_path()));
+        assert_eq!(report.summary().get(&ErrorType::IO), Some(&1));
+        assert_eq!(report.exit_code(), 2);
+    }
+}
@@ -1,28 +1,33 @@
 use quicli::prelude::*;
 use im::vector::*;
-use failure::Error;
 
-use std::io;
 use std::result::Result;
-use std::path::{Path, PathBuf};
-use std::collections::LinkedList;
+use std::path::Path;
 
 use fileutils::*;
 use errors::*;
 use operations::*;
-use config::{read_config_file, RstowConfig};
-
-pub(crate) fn stow_path<'a>(
+use config::load_config;
+use filesystem::FileSystem;
+
+/// Find operations to stow `source_path` to `target_path` depending on the target state.
+/// Operations are added to `operations`, the returned `TraversOperation` tells if children
+/// of a directory should be visited. A non empty directory is only overridden by a file with
+/// `allow_recursive_delete`.
+pub fn stow_path<'a>(
+    fs: &dyn FileSystem,
     source_path: &'a Path,
     target_path: &'a Path,
     force: bool,
     backup: bool,
+    allow_recursive_delete: bool,
     operations: &'a mut Vector<FSOperation>) -> Result<TraversOperation, AppError> {
 
-    let target_is_directory = source_path.is_dir();
-    let target_exist = target_path.exists();
-    let target_is_symlink = is_symlink(target_path);
-    let is_valid_symlink = check_symlink(target_path, source_path);
+    let target_is_directory = fs.is_dir(source_path);
+    let target_is_symlink = is_symlink(fs, target_path);
+    // a dangling symbolic link doesn't exist for Path::exists but still occupies target path
+    let target_exist = fs.exists(target_path) || target_is_symlink;
+    let is_valid_symlink = check_symlink(fs, target_path, source_path);
 
 
     debug!("Stow {} -> {}", source_path.display(), target_path.display());
@@ -32,7 +37,7 @@ pub(crate) fn stow_path<'a>(
            target_is_directory,
            target_exist,
            target_is_symlink,
-           get_symlink_target(target_path),
+           get_symlink_target(fs, target_path),
            is_valid_symlink
     );
 
@@ -65,22 +70,24 @@ pub(crate) fn stow_path<'a>(
                         Err(AppError::StowPathError {
                             source: ErrorPath::from(source_path),
                             target: ErrorPath::from(target_path),
-                            cause: "Target directory already exist as a symlink somewhere else. Not supported yet".to_string()
+                            cause: "Target directory already exist as a symlink somewhere else. Not supported yet".to_string(),
+                            hint: FORCE_HINT
                         })
                     }
                 } else {
                     if force {
                         debug!("Invalid symlink {} already exist on file. Override because of force flag.", target_path.display());
                         log!(Level::Warn, "Path symlink {} already exist and will be override", target_path.display());
-                        operations.push_back(FSOperation::Delete(target_path.to_path_buf()));
-                        operations.push_back(symlink_operation);
+                        // replaced in place, the path never disappears
+                        operations.push_back(FSOperation::ReplaceSymlink { source: source_path.to_path_buf(), target: target_path.to_path_buf() });
                         stop_if_directory()
                     } else {
                         debug!("Error: Invalid symlink {} already exist on file without force flag.", target_path.display());
                         Err(AppError::StowPathError {
                             source: ErrorPath::from(source_path),
                             target: ErrorPath::from(target_path),
-                            cause: "Target file already exist as a symlink somewhere else. Try with -f force flag to override symlink".to_string()
+                            cause: "Target file already exist as a symlink somewhere else. Try with -f force flag to override symlink".to_string(),
+                            hint: FORCE_HINT
                         })
                     }
                 }
@@ -91,9 +98,25 @@ pub(crate) fn stow_path<'a>(
             // A real file already exist and force flag is set
             if backup {
                 operations.push_back(FSOperation::Backup(target_path.to_path_buf()));
+            } else if fs.is_dir(target_path) {
+                // a real directory on the path of a source file
+                if fs.read_dir(target_path).map_err(io_error(target_path))?.is_empty() {
+                    operations.push_back(FSOperation::DeleteEmptyDir(target_path.to_path_buf()));
+                } else if allow_recursive_delete {
+                    log!(Level::Warn, "Directory {} already exist and will be deleted recursively !", target_path.display());
+                    operations.push_back(FSOperation::DeleteDirRecursive(target_path.to_path_buf()));
+                } else {
+                    debug!("Error: Non empty directory {} already exist without allow recursive delete flag.", target_path.display());
+                    return Err(AppError::StowPathError {
+                        source: ErrorPath::from(source_path),
+                        target: ErrorPath::from(target_path),
+                        cause: "Target is a non empty directory. Set --allow-recursive-delete flag to delete it or --backup to keep it".to_string(),
+                        hint: RECURSIVE_DELETE_HINT
+                    });
+                }
             } else {
                 log!(Level::Warn, "Path {} already exist and will be override !", target_path.display());
-                operations.push_back(FSOperation::Delete(target_path.to_path_buf()));
+                operations.push_back(FSOperation::DeleteFile(target_path.to_path_buf()));
             }
             operations.push_back(symlink_operation);
             Ok(TraversOperation::Continue)
@@ -104,7 +127,8 @@ pub(crate) fn stow_path<'a>(
             Err(AppError::StowPathError {
                 source: ErrorPath::from(source_path),
                 target: ErrorPath::from(target_path),
-                cause: "Target file already physically exist. Set -f flag to force override".to_string()
+                cause: "Target file already physically exist. Set -f flag to force override".to_string(),
+                hint: FORCE_HINT
             })
         }
         (true, false, true, _) => {
@@ -114,9 +138,9 @@ pub(crate) fn stow_path<'a>(
         }
         (false, _, true, _) => {
             //target is a directory
-            let config = read_config_file(source_path).unwrap_or(RstowConfig::default());
+            let config = load_config(fs, source_path)?;
 
-            println!("config {} source {}", config.symlink_current_dir, source_path.display());
+            debug!("Directory {} symlink_current_dir = {}", source_path.display(), config.symlink_current_dir);
             if config.symlink_current_dir {
                 debug!("Target directory {} not exist. Create symlink.", target_path.display());
                 operations.push_back(symlink_operation);
@@ -137,18 +161,23 @@ pub(crate) fn stow_path<'a>(
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_asref)]
 mod test_stow {
     use super::*;
     use test_utils::*;
+    use std::path::PathBuf;
     use std::borrow::BorrowMut;
     use std::fs::*;
     use std::io::Write;
     use config::RSTOW_FILE_NAME;
+    use filesystem::RealFileSystem;
 
     const FORCE: bool = true;
     const BACKUP: bool = true;
     const NO_FORCE: bool = false;
     const NO_BACKUP: bool = false;
+    const RECURSIVE_DELETE: bool = true;
+    const NO_RECURSIVE_DELETE: bool = false;
 
     #[test]
     fn test_file() {
@@ -157,7 +186,7 @@ mod test_stow {
             let target_file = target.join("file.txt");
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
@@ -166,7 +195,7 @@ mod test_stow {
             let value = iter.next().unwrap();
             assert_eq!(value, &FSOperation::CreateSymlink { source: source_file, target: target_file });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -176,7 +205,7 @@ mod test_stow {
             let target_dir = target.join("subDir");
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_dir.as_path(), target_dir.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_dir.as_path(), target_dir.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::StopPathRun);
@@ -185,7 +214,7 @@ mod test_stow {
             let value = iter.next().unwrap();
             assert_eq!(value, &FSOperation::CreateSymlink { source: source_dir, target: target_dir });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -202,7 +231,7 @@ mod test_stow {
             config_file.write_all(content.as_bytes()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_dir.as_path(), target_dir.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_dir.as_path(), target_dir.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
@@ -211,7 +240,7 @@ mod test_stow {
             let value = iter.next().unwrap();
             assert_eq!(value, &FSOperation::CreateDir(target_dir));
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -221,12 +250,12 @@ mod test_stow {
             let target_file = add_file_to("file.txt", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // return an error
-            assert!(result.is_err());
+            assert_eq!(result.unwrap_err().suggestion(), Some(FORCE_HINT));
             assert!(operations.is_empty());
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -236,13 +265,13 @@ mod test_stow {
             let target_file = add_directory_to("subDir", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // return an error
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
             assert!(operations.is_empty());
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -252,17 +281,17 @@ mod test_stow {
             let target_file = add_file_to("file.txt", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
 
             // Delete then Symlink
             let mut iter = operations.iter();
-            assert_eq!(iter.next().unwrap(), &FSOperation::Delete(target_file.to_path_buf()));
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteFile(target_file.to_path_buf()));
             assert_eq!(iter.next().unwrap(), &FSOperation::CreateSymlink { source: source_file, target: target_file });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -272,12 +301,39 @@ mod test_stow {
             let target_file = add_directory_to("subDir", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
             assert!(operations.is_empty());
-        });
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_existing_directory_on_file_with_force() {
+        with_test_directories("test_existing_directory_on_file_with_force", |source: &PathBuf, target: &PathBuf| {
+            let source_file = add_file_to("file.txt", source.as_path()).unwrap();
+            let target_dir = add_directory_to("file.txt", target.as_path()).unwrap();
+
+            let mut operations: Vector<FSOperation> = Vector::new();
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_dir.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
+            assert!(result.is_ok());
+            assert_eq!(operations.iter().next().unwrap(), &FSOperation::DeleteEmptyDir(target_dir.to_path_buf()));
+
+            add_file_to("child.txt", target_dir.as_path()).unwrap();
+            let mut operations: Vector<FSOperation> = Vector::new();
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_dir.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
+            // --force is already set
+            assert_eq!(result.unwrap_err().suggestion(), Some(RECURSIVE_DELETE_HINT));
+            assert!(operations.is_empty());
+
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_dir.as_path(), FORCE, NO_BACKUP, RECURSIVE_DELETE, operations.borrow_mut());
+            assert!(result.is_ok());
+            let mut iter = operations.iter();
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteDirRecursive(target_dir.to_path_buf()));
+            assert_eq!(iter.next().unwrap(), &FSOperation::CreateSymlink { source: source_file, target: target_dir });
+            assert_eq!(iter.next(), None);
+        }).unwrap();
     }
 
     #[test]
@@ -287,7 +343,7 @@ mod test_stow {
             let target_file = add_file_to("file.txt", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), FORCE, BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
@@ -297,7 +353,7 @@ mod test_stow {
             assert_eq!(iter.next().unwrap(), &FSOperation::Backup(target_file.to_path_buf()));
             assert_eq!(iter.next().unwrap(), &FSOperation::CreateSymlink { source: source_file, target: target_file });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -307,12 +363,12 @@ mod test_stow {
             let target_file = add_directory_to("subDir", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), FORCE, BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
             assert!(operations.is_empty());
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -320,10 +376,10 @@ mod test_stow {
         with_test_directories("test_existing_valid_link_file".as_ref(), |source: &PathBuf, target: &PathBuf| {
             let source_file = add_file_to("file.txt", source.as_path()).unwrap();
             let target_file = target.join("file.txt");
-            create_symlink(source_file.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
@@ -332,7 +388,7 @@ mod test_stow {
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::Nothing{ path: target_file.to_path_buf(), cause: "Valid symbolic link".to_owned() });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -340,10 +396,10 @@ mod test_stow {
         with_test_directories("test_existing_valid_link_directory".as_ref(),|source: &PathBuf, target: &PathBuf| {
             let source_file = add_directory_to("subDir", source.as_path()).unwrap();
             let target_file = target.join("subDir");
-            create_symlink(source_file.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // return stop directory traversing
             assert!(result.is_ok());
@@ -352,7 +408,7 @@ mod test_stow {
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::Nothing{ path: target_file.to_path_buf(), cause: "Valid symbolic link".to_owned() });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -364,15 +420,36 @@ mod test_stow {
             let other_source_dir: PathBuf = source.parent().unwrap().to_path_buf().join("somewhere");
             create_dir_all(other_source_dir.as_path()).unwrap();
             let other_source = add_file_to("file.txt", other_source_dir.as_path()).unwrap();
-            create_symlink(other_source.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, other_source.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_err());
             assert!(operations.is_empty());
-        });
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_existing_dangling_link_file() {
+        with_test_directories("test_existing_dangling_link_file", |source: &PathBuf, target: &PathBuf| {
+            let source_file = add_file_to("file.txt", source.as_path()).unwrap();
+            let target_file = target.join("file.txt");
+            create_symlink(&RealFileSystem, source.join("missing.txt").as_path(), target_file.as_path()).unwrap();
+
+            let mut operations: Vector<FSOperation> = Vector::new();
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
+            assert!(result.is_err());
+            assert!(operations.is_empty());
+
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
+            assert!(result.is_ok());
+
+            let mut iter = operations.iter();
+            assert_eq!(iter.next().unwrap(), &FSOperation::ReplaceSymlink { source: source_file, target: target_file });
+            assert_eq!(iter.next(), None);
+        }).unwrap();
     }
 
     #[test]
@@ -383,15 +460,15 @@ mod test_stow {
 
             let other_source_dir: PathBuf = source.parent().unwrap().to_path_buf().join("somewhere");
             create_dir_all(other_source_dir.as_path()).unwrap();
-            create_symlink(other_source_dir.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, other_source_dir.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), NO_FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // return stop directory traversing
             assert!(result.is_err());
             assert!(operations.is_empty());
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -403,20 +480,19 @@ mod test_stow {
             let other_source_dir: PathBuf = source.parent().unwrap().to_path_buf().join("somewhere");
             create_dir_all(other_source_dir.as_path()).unwrap();
             let other_source = add_file_to("file.txt", other_source_dir.as_path()).unwrap();
-            create_symlink(other_source.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, other_source.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
 
-            // Delete old symlink then Symlink to new source
+            // Replace old symlink by a symlink to new source
             let mut iter = operations.iter();
-            assert_eq!(iter.next().unwrap(), &FSOperation::Delete(target_file.to_path_buf()));
-            assert_eq!(iter.next().unwrap(), &FSOperation::CreateSymlink { source: source_file, target: target_file });
+            assert_eq!(iter.next().unwrap(), &FSOperation::ReplaceSymlink { source: source_file, target: target_file });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -427,16 +503,16 @@ mod test_stow {
 
             let other_source_dir: PathBuf = source.parent().unwrap().to_path_buf().join("somewhere");
             create_dir_all(other_source_dir.as_path()).unwrap();
-            create_symlink(other_source_dir.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, other_source_dir.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = stow_path(source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, operations.borrow_mut());
+            let result = stow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(), FORCE, NO_BACKUP, NO_RECURSIVE_DELETE, operations.borrow_mut());
 
             assert!(result.is_ok());
 
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::BreakDirectoryLink(target_file.to_path_buf()));
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 }
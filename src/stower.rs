@@ -0,0 +1,1048 @@
+use quicli::prelude::*;
+use im::vector::*;
+
+use std::result::Result;
+use std::path::{Path, PathBuf};
+use std::borrow::BorrowMut;
+use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::cell::RefCell;
+use std::time::Duration;
+
+use config;
+use config::{IgnoreList, RstowConfig, SourceConfigs};
+use fileutils::*;
+use operations::*;
+use errors::*;
+use graph::OperationGraph;
+use filesystem::{FileSystem, RealFileSystem};
+use hooks::{HookRunner, ShellHookRunner};
+use git::GitRepository;
+use mapping;
+use secrets;
+use system_target;
+use modes;
+use crypto;
+use secrets::SHARED_TARGET_FILE_NAME;
+use alternates;
+use alternates::Conditions;
+use report::Report;
+use interpreters;
+use interpreters::{Interpreter, DryRunInterpreter, FileSystemInterpreter};
+use lock::TargetLock;
+use stow;
+use unstow;
+use verify;
+use verify::{Drift, Verification};
+
+/// Builder of a stow (or unstow) `Plan` from a `source` directory to a `target` directory.
+///
+/// ```no_run
+/// use rstow::Stower;
+///
+/// let plan = Stower::new("./dotfiles/home", "/home/user")
+///     .force(true)
+///     .backup(true)
+///     .plan()
+///     .unwrap();
+///
+/// let report = plan.apply();
+/// report.print();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Stower {
+    source: PathBuf,
+    target: PathBuf,
+    force: bool,
+    backup: bool,
+    allow_recursive_delete: bool,
+    unstow: bool,
+    jobs: usize,
+    keep_going: bool,
+    git: bool,
+    dotfiles: bool,
+    conditions: Conditions,
+    default_ignore: bool,
+    global_ignore: Option<PathBuf>,
+    shared: bool,
+    identity: Option<PathBuf>,
+    owner: Option<u32>,
+    group: Option<u32>,
+    lock_timeout: Option<Duration>,
+    fs: Arc<dyn FileSystem>,
+    hooks: Arc<dyn HookRunner>,
+}
+
+impl Stower {
+    /// Stow `source` directory into `target` directory, without force or backup
+    pub fn new<S: AsRef<Path>, T: AsRef<Path>>(source: S, target: T) -> Stower {
+        Stower {
+            source: source.as_ref().to_path_buf(),
+            target: target.as_ref().to_path_buf(),
+            force: false,
+            backup: false,
+            allow_recursive_delete: false,
+            unstow: false,
+            jobs: 1,
+            keep_going: false,
+            git: false,
+            dotfiles: false,
+            conditions: Conditions::current(),
+            default_ignore: true,
+            global_ignore: None,
+            shared: false,
+            identity: None,
+            owner: None,
+            group: None,
+            lock_timeout: None,
+            fs: Arc::new(RealFileSystem),
+            hooks: Arc::new(ShellHookRunner),
+        }
+    }
+
+    /// Filesystem used to plan and apply operations, `RealFileSystem` by default
+    pub fn filesystem(mut self, fs: Arc<dyn FileSystem>) -> Stower {
+        self.fs = fs;
+        self
+    }
+
+    /// Runner of the hook commands applied by `Plan::apply`, `ShellHookRunner` by default
+    pub fn hooks(mut self, hooks: Arc<dyn HookRunner>) -> Stower {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Override existing files on target using a symlink
+    pub fn force(mut self, force: bool) -> Stower {
+        self.force = force;
+        self
+    }
+
+    /// Create a backup of overridden files (only used with `force`)
+    pub fn backup(mut self, backup: bool) -> Stower {
+        self.backup = backup;
+        self
+    }
+
+    /// Allow `force` to delete a non empty real directory on the path of a source file. Without
+    /// it, only empty directories are deleted.
+    pub fn allow_recursive_delete(mut self, allow_recursive_delete: bool) -> Stower {
+        self.allow_recursive_delete = allow_recursive_delete;
+        self
+    }
+
+    /// Un-stow target from source : remove symlinks and restore backups
+    pub fn unstow(mut self, unstow: bool) -> Stower {
+        self.unstow = unstow;
+        self
+    }
+
+    /// Number of threads used to apply independent operations
+    pub fn jobs(mut self, jobs: usize) -> Stower {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Apply operations not depending on a failed path instead of aborting on the first error
+    pub fn keep_going(mut self, keep_going: bool) -> Stower {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Only stow files tracked by the git repository of `source`. Without it, `.gitignore`
+    /// rules of the repository (if any) are still honoured.
+    pub fn git(mut self, git: bool) -> Stower {
+        self.git = git;
+        self
+    }
+
+    /// Stow source entries prefixed by `dot-` as hidden files : `dot-bashrc` is linked to `.bashrc`
+    pub fn dotfiles(mut self, dotfiles: bool) -> Stower {
+        self.dotfiles = dotfiles;
+        self
+    }
+
+    /// Conditions choosing between alternate source files like `.zshrc##hostname.laptop`,
+    /// those of the current system by default
+    pub fn conditions(mut self, conditions: Conditions) -> Stower {
+        self.conditions = conditions;
+        self
+    }
+
+    /// Add a class to the conditions choosing between alternate files (`file##class.work`)
+    pub fn class<S: Into<String>>(mut self, class: S) -> Stower {
+        self.conditions.classes.push(class.into());
+        self
+    }
+
+    /// Ignore built-in patterns (`README*`, `LICENSE*`, editor swap files...) in every source
+    /// directory, enabled by default. `.rstow` files and `.git` directories are never stowed.
+    pub fn default_ignore(mut self, default_ignore: bool) -> Stower {
+        self.default_ignore = default_ignore;
+        self
+    }
+
+    /// File of patterns (one per line) ignored in every source directory, like `~/.rstow-global-ignore`.
+    /// A missing file is not an error.
+    pub fn global_ignore<P: AsRef<Path>>(mut self, file: P) -> Stower {
+        self.global_ignore = Some(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Target is shared with other users : source files looking like secrets (private keys, `.env`...)
+    /// are conflicts unless allowed by `allow_secrets` in `.rstow`. A target containing a
+    /// `.rstow-shared` file is always shared.
+    pub fn shared(mut self, shared: bool) -> Stower {
+        self.shared = shared;
+        self
+    }
+
+    /// age identity file (`AGE-SECRET-KEY-...` lines) decrypting source files suffixed by `.age`
+    /// into real files, instead of linking them
+    pub fn identity<P: AsRef<Path>>(mut self, file: P) -> Stower {
+        self.identity = Some(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// User id given to directories and decrypted files created on target (useful when stowing
+    /// into `/etc` with sudo)
+    pub fn owner(mut self, uid: u32) -> Stower {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// Group id given to directories and decrypted files created on target
+    pub fn group(mut self, gid: u32) -> Stower {
+        self.group = Some(gid);
+        self
+    }
+
+    /// Lock the target directory (see `TargetLock`) when planning, waiting up to `timeout` for
+    /// another run holding it. The lock is held until the plan (and its copies) is dropped.
+    /// Plans are not locked by default, like previews that don't change the target.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Stower {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Source directory (or file) to stow
+    pub fn source(&self) -> &Path {
+        self.source.as_path()
+    }
+
+    /// Target directory
+    pub fn target(&self) -> &Path {
+        self.target.as_path()
+    }
+
+    /// Interpreter applying operations with the filesystem and hook runner of this stower, like
+    /// `Plan::apply`
+    pub(crate) fn interpreter(&self) -> FileSystemInterpreter {
+        FileSystemInterpreter::new(self.fs.clone()).hooks(self.hooks.clone())
+    }
+
+    /// Traverse recursively (if directory) `source` and build paths relative to `target` to find
+    /// operations to do. Conflicts found on target paths are part of the plan, only invalid
+    /// `source` or `target` paths (or a target locked by another run) are returned as error.
+    pub fn plan(&self) -> Result<Plan, AppError> {
+        let fs = self.fs.as_ref();
+        let source = absolute_path(fs, self.source.as_path())?;
+        let target = absolute_path(fs, self.target.as_path())?;
+        let lock = self.lock(target.as_path())?;
+
+        info!("Stow from Source {:?} to target {:?}", source.display(), target.display());
+
+        let mut operations = OperationGraph::new();
+        let traversed = Traversal::new(self, source.as_path(), target.as_path())
+            .and_then(|traversal| traversal.traverse_fs(source.as_path(), target.as_path(), &mut operations));
+        if let Err(e) = traversed {
+            operations.push(Err(e));
+        }
+
+        let operations = self.with_hooks(source.as_path(), target.as_path(), operations);
+        Ok(self.new_plan(operations, lock))
+    }
+
+    /// Plan operations for changed paths of the `source` directory only : existing paths are
+    /// stowed and removed ones are un-stowed. Paths outside of `source` are ignored.
+    pub fn plan_paths<P: AsRef<Path>>(&self, paths: &[P]) -> Result<Plan, AppError> {
+        let fs = self.fs.as_ref();
+        let source = absolute_path(fs, self.source.as_path())?;
+        let target = absolute_path(fs, self.target.as_path())?;
+        let lock = self.lock(target.as_path())?;
+
+        let mut changed: Vec<&Path> = paths.iter()
+            .map(|p| p.as_ref())
+            .filter(|p| p.starts_with(source.as_path()) && *p != source.as_path())
+            .collect();
+        changed.sort();
+        // children of a changed directory are planned with it
+        changed.dedup_by(|child, parent| child.starts_with(*parent));
+
+        let mut operations = OperationGraph::new();
+        let traversal = match Traversal::new(self, source.as_path(), target.as_path()) {
+            Ok(traversal) => traversal,
+            Err(e) => {
+                operations.push(Err(e));
+                return Ok(self.new_plan(operations, lock));
+            }
+        };
+        for path in changed {
+            let target_path = match traversal.target_path(source.as_path(), target.as_path(), path) {
+                Ok(Some(target_path)) => target_path,
+                Ok(None) => continue,
+                Err(e) => {
+                    operations.push(Err(e));
+                    continue;
+                }
+            };
+            let result = if fs.exists(path) || fs.is_symlink(path) {
+                traversal.visit_tree(path, target_path.as_path(), &mut operations)
+            } else {
+                traversal.visit_removed(path, target_path.as_path(), &mut operations)
+            };
+            if let Err(e) = result {
+                operations.push(Err(e));
+            }
+        }
+
+        let operations = self.with_hooks(source.as_path(), target.as_path(), operations);
+        Ok(self.new_plan(operations, lock))
+    }
+
+    /// Compare target with source without changing anything : each deployed source file must be
+    /// linked (same file) or copied with the same content, and files of linked directories must
+    /// be part of the source. Only invalid `source` or `target` paths are returned as error.
+    pub fn verify(&self) -> Result<Verification, AppError> {
+        let fs = self.fs.as_ref();
+        let source = absolute_path(fs, self.source.as_path())?;
+        let target = absolute_path(fs, self.target.as_path())?;
+
+        let mut verification = Verification::default();
+        match Traversal::new(self, source.as_path(), target.as_path()) {
+            Ok(traversal) => traversal.verify_dir(source.as_path(), target.as_path(), &mut verification),
+            Err(e) => verification.push_error(e),
+        }
+        Ok(verification)
+    }
+
+    /// Lock of the absolute `target` path if locking is enabled
+    fn lock(&self, target: &Path) -> Result<Option<Arc<TargetLock>>, AppError> {
+        match self.lock_timeout {
+            Some(timeout) => Ok(Some(Arc::new(TargetLock::acquire(target, timeout)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn new_plan(&self, operations: OperationGraph, lock: Option<Arc<TargetLock>>) -> Plan {
+        Plan { operations, jobs: self.jobs, keep_going: self.keep_going, fs: self.fs.clone(), hooks: self.hooks.clone(), lock }
+    }
+
+    /// Surround operations with the pre and post hooks of the `.rstow` file of `source`,
+    /// only if some target path is changed. Hooks of `.rstow` files in sub-directories are
+    /// ignored.
+    fn with_hooks(&self, source: &Path, target: &Path, operations: OperationGraph) -> OperationGraph {
+        let fs = self.fs.as_ref();
+        let mut changed: Vec<PathBuf> = operations.operations()
+            .filter_map(|res_op| res_op.as_ref().ok())
+            .flat_map(|op| [op.produces(), op.removes()].concat())
+            .collect();
+        if changed.is_empty() {
+            return operations;
+        }
+        changed.sort();
+        changed.dedup();
+
+        let directory = if fs.is_dir(source) { source } else { source.parent().unwrap_or(source) };
+        // configuration errors are already reported by the traversal
+        let config = match config::load_config(fs, directory) {
+            Ok(config) => config,
+            Err(_) => return operations,
+        };
+        let hook = |(hook, command): (&str, &str)| Ok(FSOperation::RunHook {
+            hook: hook.to_string(),
+            command: command.to_string(),
+            source: directory.to_path_buf(),
+            target: target.to_path_buf(),
+            changed: changed.clone(),
+        });
+
+        let (pre, post) = RstowConfig::hooks(&config, self.unstow);
+        if pre.is_none() && post.is_none() {
+            return operations;
+        }
+        let mut hooked = OperationGraph::new();
+        if let Some(pre) = pre {
+            hooked.push(hook(pre));
+        }
+        for res_op in operations.operations() {
+            hooked.push(res_op.clone());
+        }
+        if let Some(post) = post {
+            hooked.push(hook(post));
+        }
+        hooked
+    }
+}
+
+/// Operations planned by a `Stower`, to inspect, filter and apply
+#[derive(Debug, Clone)]
+pub struct Plan {
+    operations: OperationGraph,
+    jobs: usize,
+    keep_going: bool,
+    fs: Arc<dyn FileSystem>,
+    hooks: Arc<dyn HookRunner>,
+    // target lock shared by filtered copies of the plan
+    lock: Option<Arc<TargetLock>>,
+}
+
+impl Plan {
+    /// Operations dependency graph
+    pub fn graph(&self) -> &OperationGraph {
+        &self.operations
+    }
+
+    /// Planned operations (or planning errors) in application order
+    pub fn operations(&self) -> impl Iterator<Item = &Result<FSOperation, AppError>> {
+        self.operations.operations()
+    }
+
+    /// Planning errors (conflicts, invalid configurations...)
+    pub fn errors(&self) -> impl Iterator<Item = &AppError> {
+        self.operations.errors()
+    }
+
+    /// New plan keeping only operations matching `predicate`. Planning errors are kept.
+    pub fn filter<P: Fn(&FSOperation) -> bool>(&self, predicate: P) -> Plan {
+        let mut operations = OperationGraph::new();
+        for res_op in self.operations() {
+            match res_op {
+                Ok(op) if !predicate(op) => (),
+                _ => { operations.push(res_op.clone()); },
+            }
+        }
+        Plan { operations, jobs: self.jobs, keep_going: self.keep_going, fs: self.fs.clone(), hooks: self.hooks.clone(), lock: self.lock.clone() }
+    }
+
+    /// Log operations that should be done without affecting files
+    pub fn dry_run(&self) -> Report {
+        self.run(&DryRunInterpreter::new(self.fs.clone()))
+    }
+
+    /// Apply operations on filesystem. Nothing is applied if the plan contains errors,
+    /// unless `keep_going` is set.
+    pub fn apply(&self) -> Report {
+        self.run(&FileSystemInterpreter::new(self.fs.clone()).hooks(self.hooks.clone()))
+    }
+
+    /// Execute operations with `interpreter`. A preview interpreter is given every operation
+    /// and planning error, others follow `apply` rules.
+    pub fn run(&self, interpreter: &dyn Interpreter) -> Report {
+        let mut report = self.check();
+        if interpreter.is_preview() {
+            report.add_all(interpreters::preview_graph(interpreter, &self.operations));
+            return report;
+        }
+
+        let plan_valid = !report.errors().iter().any(|e| matches!(e, AppError::InvalidPlan { .. }));
+
+        if plan_valid && (self.keep_going || report.is_empty()) {
+            // a failure only stops and rolls back its own group, whatever the number of jobs
+            let result = interpreters::apply_graph(interpreter, &self.operations, self.jobs, self.keep_going);
+            report.add_all(result.errors);
+            report.add_skipped(result.skipped);
+            report.add_rolled_back(result.rolled_back);
+        }
+        report
+    }
+
+    /// Report with planning errors and plan consistency errors
+    fn check(&self) -> Report {
+        let mut report = Report::new();
+        report.add_all(self.operations.errors().cloned());
+        report.add_all(self.operations.validate());
+        report
+    }
+}
+
+fn absolute_path(fs: &dyn FileSystem, path: &Path) -> Result<PathBuf, AppError> {
+    fs.canonicalize(path).map_err(|e| AppError::InvalidPath {
+        path: ErrorPath::from(path),
+        cause: format!("Unresolved absolute path ({})", e)
+    })
+}
+
+/// Options and source filters used when traversing the source directory
+struct Traversal<'a> {
+    fs: &'a dyn FileSystem,
+    force: bool,
+    backup: bool,
+    allow_recursive_delete: bool,
+    unstow: bool,
+    dotfiles: bool,
+    conditions: Conditions,
+    ignores: IgnoreList,
+    git: Option<GitRepository>,
+    shared: bool,
+    identity: Option<PathBuf>,
+    owner: Option<u32>,
+    group: Option<u32>,
+    // target owned by root
+    system_target: bool,
+    source: PathBuf,
+    target: PathBuf,
+    configs: SourceConfigs<'a>,
+    // directories created by the plan so far
+    created: RefCell<BTreeSet<PathBuf>>,
+}
+
+impl<'a> Traversal<'a> {
+    fn new(stower: &'a Stower, source: &Path, target: &Path) -> Result<Traversal<'a>, AppError> {
+        let fs = stower.fs.as_ref();
+        let git = GitRepository::find(fs, source, stower.git)?;
+        if stower.git && git.is_none() {
+            return Err(AppError::GitError { path: ErrorPath::from(source), cause: "Source is not in a git repository".to_string() });
+        }
+        let ignores = IgnoreList::new(fs, stower.default_ignore, stower.global_ignore.as_deref())?;
+        Ok(Traversal {
+            fs,
+            force: stower.force,
+            backup: stower.backup,
+            allow_recursive_delete: stower.allow_recursive_delete,
+            unstow: stower.unstow,
+            dotfiles: stower.dotfiles,
+            conditions: stower.conditions.clone(),
+            ignores,
+            git,
+            shared: stower.shared || fs.exists(target.join(SHARED_TARGET_FILE_NAME).as_path()),
+            identity: stower.identity.clone(),
+            owner: stower.owner,
+            group: stower.group,
+            system_target: fs.owner(target).map(|(uid, _)| uid == 0).unwrap_or(false),
+            source: source.to_path_buf(),
+            target: target.to_path_buf(),
+            configs: SourceConfigs::new(fs, source),
+            created: RefCell::new(BTreeSet::new()),
+        })
+    }
+
+    fn traverse_fs(&self, source: &Path, target: &Path, operations: &mut OperationGraph) -> Result<(), AppError> {
+        let fs = self.fs;
+
+        if fs.is_dir(source) {
+            let config = match self.configs.get(source) {
+                Ok(config) => config,
+                Err(e) => {
+                    operations.push(Err(e));
+                    return Ok(());
+                }
+            };
+            let source_paths = fs.read_dir(source).map_err(io_error(source))?;
+            let mut entries: Vec<(&Path, &str)> = Vec::new();
+            for path in source_paths.iter() {
+                match file_name_str(path.as_path()) {
+                    Ok(name) => entries.push((path.as_path(), name)),
+                    Err(e) => { operations.push(Err(e)); },
+                }
+            }
+            let selected = alternates::select(entries.iter().map(|(_, name)| *name), &self.conditions);
+
+            for (path, name) in entries {
+                if let Some(target_name) = self.target_name(&config, &selected, name, path) {
+                    let target_file_path = match self.mapped_target(path)? {
+                        Some(mapped) => {
+                            self.create_parent(mapped.as_path(), operations);
+                            mapped
+                        },
+                        None => target.join(target_name),
+                    };
+                    self.visit_tree(path, target_file_path.as_path(), operations)?;
+                }
+            }
+        } else {
+            visit_node(fs, source, target, self.force, self.backup, self.allow_recursive_delete, self.unstow, operations.borrow_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Visit `source` then its children if needed
+    fn visit_tree(&self, source: &Path, target: &Path, operations: &mut OperationGraph) -> Result<(), AppError> {
+        let fs = self.fs;
+        if !self.unstow {
+            if let Some(error) = modes::mode_error(&self.configs, self.target.as_path(), source, target)? {
+                operations.push(Err(error));
+            }
+        }
+        let planned = operations.len();
+        if !fs.is_dir(source) && crypto::decrypted_name(file_name_str(source)?).is_some() {
+            self.visit_encrypted(source, target, operations)?;
+            self.set_created_owner(planned, operations);
+            return Ok(());
+        }
+        let must_create = || -> Result<bool, AppError> { Ok(mapping::has_mapped_children(&self.configs, source)? || self.has_encrypted_children(source)?) };
+        if !self.unstow && fs.is_dir(source) && !fs.exists(target) && !fs.is_symlink(target) && must_create()? {
+            debug!("Directory {} has mapped or encrypted children, create it instead of linking it", source.display());
+            self.create_dir(target, operations);
+            self.set_created_mode(source, target, planned, operations)?;
+            return self.traverse_fs(source, target, operations);
+        }
+
+        let mut node_operations = OperationGraph::new();
+        let visited = visit_node(self.fs, source, target, self.force, self.backup, self.allow_recursive_delete, self.unstow, &mut node_operations);
+        let secrets = if self.shared && !self.unstow { secrets::linked_secrets(&self.configs, &node_operations)? } else { Vec::new() };
+        if secrets.is_empty() {
+            // errors come first : operations on the linked target depend on them
+            if !self.unstow && self.system_target {
+                let owner = self.owner.unwrap_or(0);
+                for error in system_target::linked_source_errors(self.fs, self.source.as_path(), owner, &node_operations)? {
+                    operations.push(Err(error));
+                }
+            }
+            for res_op in node_operations.operations() {
+                operations.push(res_op.clone());
+            }
+        } else {
+            // operations of a node linking a secret are replaced by conflicts
+            for secret in secrets {
+                operations.push(Err(secret));
+            }
+        }
+        if !self.unstow {
+            self.set_created_owner(planned, operations);
+            self.set_created_mode(source, target, planned, operations)?;
+        }
+        match visited {
+            Ok(TraversOperation::StopPathRun) => (),
+            Ok(TraversOperation::Continue) => {
+                if self.fs.is_dir(source) {
+                    self.traverse_fs(source, target, operations)?;
+                }
+            },
+            Err(e) => error!("{}", e),
+        }
+        Ok(())
+    }
+
+    /// Un-stow a removed source path : links to it (or to its removed children) are deleted
+    fn visit_removed(&self, source: &Path, target: &Path, operations: &mut OperationGraph) -> Result<(), AppError> {
+        let fs = self.fs;
+        if fs.is_symlink(target) {
+            // target names may differ from source names, follow links back to the source
+            match fs.read_link(target) {
+                Ok(link) if link.starts_with(source) => { visit_node(fs, link.as_path(), target, false, false, false, true, operations)?; },
+                _ => (),
+            }
+        } else if fs.is_dir(target) {
+            for target_child in fs.read_dir(target).map_err(io_error(target))? {
+                self.visit_removed(source, target_child.as_path(), operations)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Target path of a source path below `source`, computed like `traverse_fs` does. `None` if the
+    /// path (or one of its parents) is ignored or if a parent directory is already linked on target.
+    fn target_path(&self, source: &Path, target: &Path, path: &Path) -> Result<Option<PathBuf>, AppError> {
+        let mut source_path = source.to_path_buf();
+        let mut target_path = target.to_path_buf();
+        for component in path.strip_prefix(source).unwrap_or(path).components() {
+            if source_path != source && check_symlink(self.fs, target_path.as_path(), source_path.as_path()) {
+                debug!("File {} already stowed by a parent directory link", path.display());
+                return Ok(None);
+            }
+
+            let config = self.configs.get(source_path.as_path())?;
+            let siblings = self.fs.read_dir(source_path.as_path()).map_err(io_error(source_path.as_path()))?;
+            source_path.push(component);
+            let name = file_name_str(source_path.as_path())?;
+
+            let selected = if self.fs.exists(source_path.as_path()) || self.fs.is_symlink(source_path.as_path()) {
+                alternates::select(siblings.iter().filter_map(|p| file_name_str(p.as_path()).ok()), &self.conditions)
+            } else {
+                // a removed alternate file was linked to its base name
+                vec![(name, alternates::split_alternate(name).0)].into_iter().collect()
+            };
+            match self.target_name(&config, &selected, name, source_path.as_path()) {
+                Some(target_name) => target_path.push(target_name),
+                None => return Ok(None),
+            }
+            if let Some(mapped) = self.mapped_target(source_path.as_path())? {
+                target_path = mapped;
+            }
+        }
+        Ok(Some(target_path))
+    }
+
+    /// Decrypt an encrypted source file into a real target file, or securely delete it on un-stow.
+    /// An existing target is left as is if it has the decrypted content.
+    fn visit_encrypted(&self, source: &Path, target: &Path, operations: &mut OperationGraph) -> Result<(), AppError> {
+        let fs = self.fs;
+        let identity = match &self.identity {
+            Some(identity) => identity,
+            None => {
+                operations.push(Err(AppError::DecryptError { path: ErrorPath::from(source), cause: "No age identity file given".to_string() }));
+                return Ok(());
+            }
+        };
+        let conflict = |cause: &str, hint: &'static str| Err(AppError::StowPathError {
+            source: ErrorPath::from(source),
+            target: ErrorPath::from(target),
+            cause: cause.to_string(),
+            hint
+        });
+        let target_is_file = fs.exists(target) && !fs.is_symlink(target) && !fs.is_dir(target);
+        let up_to_date = || -> Result<bool, AppError> { Ok(target_is_file && fs.read(target).map_err(io_error(target))? == crypto::decrypt(fs, identity, source)?) };
+
+        if self.unstow {
+            if !target_is_file {
+                operations.push(Ok(FSOperation::Nothing { path: target.to_path_buf(), cause: "No decrypted file".to_string() }));
+                return Ok(());
+            }
+            match up_to_date() {
+                Ok(true) => { operations.push(Ok(FSOperation::SecureDelete(target.to_path_buf()))); },
+                Ok(false) if self.force => { operations.push(Ok(FSOperation::SecureDelete(target.to_path_buf()))); },
+                Ok(false) => { operations.push(conflict("Decrypted file was modified. Set -f flag to delete it anyway", MODIFIED_DECRYPTED_HINT)); },
+                Err(e) => { operations.push(Err(e)); },
+            }
+            return Ok(());
+        }
+
+        if self.shared && !secrets::is_allowed_secret(&self.configs, source)? {
+            operations.push(Err(AppError::SecretError {
+                source: ErrorPath::from(source),
+                target: ErrorPath::from(target),
+                cause: "encrypted file would be decrypted".to_string()
+            }));
+            return Ok(());
+        }
+        let decrypt = FSOperation::Decrypt { source: source.to_path_buf(), target: target.to_path_buf(), identity: identity.to_path_buf() };
+        if !fs.exists(target) && !fs.is_symlink(target) {
+            operations.push(Ok(decrypt));
+            return Ok(());
+        }
+        match up_to_date() {
+            Ok(true) => { operations.push(Ok(FSOperation::Nothing { path: target.to_path_buf(), cause: "Decrypted file up to date".to_string() })); },
+            Ok(false) if self.force && !fs.is_dir(target) => {
+                if self.backup {
+                    operations.push(Ok(FSOperation::Backup(target.to_path_buf())));
+                } else {
+                    let delete = if fs.is_symlink(target) { FSOperation::DeleteSymlink(target.to_path_buf()) } else { FSOperation::DeleteFile(target.to_path_buf()) };
+                    operations.push(Ok(delete));
+                }
+                operations.push(Ok(decrypt));
+            },
+            // --force doesn't delete a directory in place of a decrypted file
+            Ok(false) if fs.is_dir(target) => { operations.push(conflict("Target is a directory", DIRECTORY_HINT)); },
+            Ok(false) => { operations.push(conflict("Target file already exist. Set -f flag to force override", FORCE_HINT)); },
+            Err(e) => { operations.push(Err(e)); },
+        }
+        Ok(())
+    }
+
+    /// Encrypted files are decrypted one by one, their directory can't be linked as a whole
+    fn has_encrypted_children(&self, directory: &Path) -> Result<bool, AppError> {
+        for child in self.fs.read_dir(directory).map_err(io_error(directory))? {
+            let encrypted = if self.fs.is_dir(child.as_path()) {
+                !self.fs.is_symlink(child.as_path()) && self.has_encrypted_children(child.as_path())?
+            } else {
+                child.file_name().and_then(|n| n.to_str()).and_then(crypto::decrypted_name).is_some()
+            };
+            if encrypted {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Give the required mode of `source` to its `target` directory if created by operations
+    /// planned since `planned`
+    fn set_created_mode(&self, source: &Path, target: &Path, planned: usize, operations: &mut OperationGraph) -> Result<(), AppError> {
+        let created = operations.nodes()[planned..].iter()
+            .any(|node| matches!(&node.operation, Ok(FSOperation::CreateDir(p)) if p == target));
+        if created {
+            if let Some(mode) = modes::required_mode(&self.configs, source)? {
+                operations.push(Ok(FSOperation::SetMode { path: target.to_path_buf(), mode }));
+            }
+        }
+        Ok(())
+    }
+
+    fn mapped_target(&self, path: &Path) -> Result<Option<PathBuf>, AppError> {
+        mapping::mapped_target(&self.configs, self.target.as_path(), path)
+    }
+
+    /// Create missing parent directories of a mapped target path
+    fn create_parent(&self, target: &Path, operations: &mut OperationGraph) {
+        match target.parent() {
+            Some(parent) if !self.unstow && !self.fs.exists(parent) => self.create_dir(parent, operations),
+            _ => (),
+        }
+    }
+
+    fn create_dir(&self, directory: &Path, operations: &mut OperationGraph) {
+        if self.created.borrow_mut().insert(directory.to_path_buf()) {
+            let planned = operations.len();
+            operations.push(Ok(FSOperation::CreateDir(directory.to_path_buf())));
+            self.set_created_owner(planned, operations);
+        }
+    }
+
+    /// Give the owner and group options to directories and decrypted files created by operations
+    /// planned since `planned`
+    fn set_created_owner(&self, planned: usize, operations: &mut OperationGraph) {
+        if self.owner.is_none() && self.group.is_none() {
+            return;
+        }
+        let created: Vec<PathBuf> = operations.nodes()[planned..].iter()
+            .filter_map(|node| match &node.operation {
+                Ok(FSOperation::CreateDir(p)) => Some(p.to_path_buf()),
+                Ok(FSOperation::Decrypt { target, .. }) => Some(target.to_path_buf()),
+                _ => None,
+            })
+            .collect();
+        for path in created {
+            operations.push(Ok(FSOperation::SetOwner { path, uid: self.owner, gid: self.group }));
+        }
+    }
+
+    /// Children of `source` directory with their target path, `None` if not deployed
+    fn children(&self, source: &Path, target: &Path) -> Result<Vec<(PathBuf, Option<PathBuf>)>, AppError> {
+        let config = self.configs.get(source)?;
+        let paths = self.fs.read_dir(source).map_err(io_error(source))?;
+        let names = paths.iter().map(|p| file_name_str(p.as_path())).collect::<Result<Vec<&str>, AppError>>()?;
+        let selected = alternates::select(names.iter().cloned(), &self.conditions);
+
+        let mut children = Vec::new();
+        for (path, name) in paths.iter().zip(names) {
+            let target_path = match self.target_name(&config, &selected, name, path) {
+                Some(target_name) => Some(self.mapped_target(path)?.unwrap_or_else(|| target.join(target_name))),
+                None => None,
+            };
+            children.push((path.to_path_buf(), target_path));
+        }
+        Ok(children)
+    }
+
+    fn verify_dir(&self, source: &Path, target: &Path, verification: &mut Verification) {
+        match self.children(source, target) {
+            Ok(children) => for (path, target_path) in children {
+                if let Some(target_path) = target_path {
+                    self.verify_path(path.as_path(), target_path.as_path(), verification);
+                }
+            },
+            Err(e) => verification.push_error(e),
+        }
+    }
+
+    fn verify_path(&self, source: &Path, target: &Path, verification: &mut Verification) {
+        let fs = self.fs;
+        let compared = if fs.is_dir(source) {
+            if fs.is_symlink(target) && fs.is_same_file(source, target) {
+                self.verify_linked_dir(source, target, verification);
+            } else if fs.is_dir(target) {
+                // partially folded : children are linked (or copied) one by one
+                self.verify_dir(source, target, verification);
+            } else {
+                let cause = if fs.exists(target) { "target is not a directory" } else { "missing on target" };
+                verification.push(Drift::new(Some(source), target, cause));
+            }
+            return;
+        } else if file_name_str(source).ok().and_then(crypto::decrypted_name).is_some() {
+            match &self.identity {
+                Some(identity) => crypto::decrypt(fs, identity, source)
+                    .and_then(|content| verify::compare_file(fs, source, target, Some(&content))),
+                None => {
+                    debug!("No identity to verify decrypted file {}", target.display());
+                    return;
+                }
+            }
+        } else {
+            verify::compare_file(fs, source, target, None)
+        };
+        match compared {
+            Ok(Some(drift)) => verification.push(drift),
+            Ok(None) => (),
+            Err(e) => verification.push_error(e),
+        }
+    }
+
+    /// A linked directory shows the source directory itself : ignored source files (README,
+    /// not selected alternate files...) are expected there, only entries missing from the source
+    /// directory are reported
+    fn verify_linked_dir(&self, source: &Path, target: &Path, verification: &mut Verification) {
+        let entries = match self.fs.read_dir(target) {
+            Ok(entries) => entries,
+            Err(e) => {
+                verification.push_error(io_error(target)(e));
+                return;
+            }
+        };
+        for name in entries.iter().filter_map(|path| path.file_name()) {
+            let source_path = source.join(name);
+            if !self.fs.exists(source_path.as_path()) && !self.fs.is_symlink(source_path.as_path()) {
+                verification.push(Drift::new(None, target.join(name).as_path(), "not part of the source"));
+            }
+        }
+    }
+
+    /// Name on target of a source entry, `None` if the entry is ignored or is an alternate file
+    /// not selected for the current conditions
+    fn target_name(&self, config: &RstowConfig, selected: &BTreeMap<&str, &str>, name: &str, path: &Path) -> Option<String> {
+        let base = match selected.get(name) {
+            Some(base) => *base,
+            None => {
+                debug!("Alternate file {} not selected", path.display());
+                return None;
+            }
+        };
+        if self.is_ignored(config, name, path) || (base != name && self.is_ignored(config, base, path)) {
+            debug!("File {} ignored", path.display());
+            return None;
+        }
+        let target_name = RstowConfig::target_name(config, base, self.dotfiles);
+        match crypto::decrypted_name(target_name.as_str()) {
+            Some(decrypted) if !self.fs.is_dir(path) => Some(decrypted.to_string()),
+            _ => Some(target_name),
+        }
+    }
+
+    fn is_ignored(&self, config: &RstowConfig, name: &str, path: &Path) -> bool {
+        self.ignores.is_ignored(name) || RstowConfig::is_ignored(config, name) || self.is_git_ignored(path)
+    }
+
+    fn is_git_ignored(&self, path: &Path) -> bool {
+        self.git.as_ref().is_some_and(|git| git.is_ignored(self.fs, path))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn visit_node(fs: &dyn FileSystem, source: &Path, target: &Path, force: bool, backup: bool, allow_recursive_delete: bool, unstow: bool, operations: &mut OperationGraph) -> Result<TraversOperation, AppError> {
+
+    let mut node_operations: Vector<FSOperation> = Vector::new();
+    let travers_result = {
+        if unstow {
+            unstow::unstow_path(fs, source, target, node_operations.borrow_mut())
+        } else {
+            stow::stow_path(fs, source, target, force, backup, allow_recursive_delete, node_operations.borrow_mut())
+        }
+    };
+
+    match travers_result {
+        Ok(travers_op) => {
+            for op in node_operations {
+                operations.push(Ok(op));
+            }
+            Ok(travers_op)
+        },
+        Err(e) => {
+            operations.push(Err(e));
+            Ok(TraversOperation::Continue)
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_stower {
+    use super::*;
+    use interpreters::RecordingInterpreter;
+    use test_utils::memory_source;
+
+    #[test]
+    fn test_plan_and_apply() {
+        let fs = memory_source();
+        let plan = Stower::new("/source", "/target").filesystem(fs.clone()).plan().unwrap();
+
+        assert_eq!(plan.operations().count(), 2);
+        assert!(plan.apply().is_empty());
+
+        assert_eq!(fs.read_link(Path::new("/target/file.txt")).unwrap(), PathBuf::from("/source/file.txt"));
+        assert_eq!(fs.read(Path::new("/target/dir/child.txt")).unwrap(), b"child".to_vec());
+    }
+
+    #[test]
+    fn test_stow_then_unstow() {
+        let fs = memory_source();
+        fs.add_file("/target/file.txt", b"existing").unwrap();
+
+        let report = Stower::new("/source", "/target").filesystem(fs.clone()).force(true).backup(true).plan().unwrap().apply();
+        assert!(report.is_empty());
+        assert!(fs.is_symlink(Path::new("/target/file.txt")));
+        assert!(fs.exists(Path::new("/target/file.txt.backup")));
+
+        let report = Stower::new("/source", "/target").filesystem(fs.clone()).unstow(true).plan().unwrap().apply();
+        assert!(report.is_empty());
+        assert_eq!(fs.read(Path::new("/target/file.txt")).unwrap(), b"existing".to_vec());
+        assert!(!fs.exists(Path::new("/target/dir")));
+    }
+
+    #[test]
+    fn test_plan_filter() {
+        let fs = memory_source();
+        let plan = Stower::new("/source", "/target").filesystem(fs.clone()).plan().unwrap()
+            .filter(|op| op.produces().iter().all(|p| p != Path::new("/target/dir")));
+
+        assert_eq!(plan.operations().count(), 1);
+        assert!(plan.apply().is_empty());
+        assert!(!fs.exists(Path::new("/target/dir")));
+    }
+
+    #[test]
+    fn test_plan_changed_paths() {
+        let fs = memory_source();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+        assert!(stower.plan().unwrap().apply().is_empty());
+
+        fs.add_file("/source/new.txt", b"new").unwrap();
+        fs.add_file("/source/dir/other.txt", b"other").unwrap();
+        fs.remove_file(Path::new("/source/file.txt")).unwrap();
+
+        let plan = stower.plan_paths(&["/source/new.txt", "/source/dir/other.txt", "/source/file.txt", "/elsewhere"]).unwrap();
+        // new file in a linked directory is already stowed
+        assert_eq!(plan.operations().count(), 2);
+        assert!(plan.apply().is_empty());
+
+        assert_eq!(fs.read_link(Path::new("/target/new.txt")).unwrap(), PathBuf::from("/source/new.txt"));
+        assert!(!fs.is_symlink(Path::new("/target/file.txt")));
+    }
+
+    #[test]
+    fn test_run_custom_interpreter() {
+        let fs = memory_source();
+        let plan = Stower::new("/source", "/target").filesystem(fs.clone()).plan().unwrap();
+
+        let recorder = RecordingInterpreter::new();
+        assert!(plan.run(&recorder).is_empty());
+
+        let operations: Vec<FSOperation> = plan.operations().filter_map(|op| op.as_ref().ok().cloned()).collect();
+        assert_eq!(recorder.operations(), operations);
+        // recording doesn't affect files
+        assert!(!fs.exists(Path::new("/target/file.txt")));
+    }
+
+    #[test]
+    fn test_stower_interpreter() {
+        let fs = memory_source();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+        assert!(stower.plan().unwrap().run(&stower.interpreter()).is_empty());
+        assert_eq!(fs.read_link(Path::new("/target/file.txt")).unwrap(), PathBuf::from("/source/file.txt"));
+    }
+
+    #[test]
+    fn test_apply_failure_same_for_any_jobs() {
+        let apply = |jobs: usize| {
+            let fs = memory_source();
+            let plan = Stower::new("/source", "/target").filesystem(fs.clone()).jobs(jobs).plan().unwrap();
+            // the target changes between planning and applying
+            fs.add_file("/target/file.txt", b"existing").unwrap();
+
+            let report = plan.apply();
+            assert_eq!(report.errors().len(), 1);
+            (fs.read(Path::new("/target/file.txt")).unwrap(), fs.read_link(Path::new("/target/dir")).unwrap())
+        };
+
+        let state = apply(1);
+        assert_eq!(state, (b"existing".to_vec(), PathBuf::from("/source/dir")));
+        assert_eq!(apply(4), state);
+    }
+}
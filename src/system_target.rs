@@ -0,0 +1,133 @@
+use quicli::prelude::*;
+
+use std::path::Path;
+use std::result::Result;
+
+use errors::{io_error, AppError, ErrorPath};
+use filesystem::FileSystem;
+use graph::OperationGraph;
+use operations::FSOperation;
+
+/// Sources (in the `source_root` directory) linked by `operations` from a system-owned target
+/// must not be writable by other users than their owner, and linking to sources another user
+/// than `target_owner` can modify is warned
+pub(crate) fn linked_source_errors(fs: &dyn FileSystem, source_root: &Path, target_owner: u32, operations: &OperationGraph) -> Result<Vec<AppError>, AppError> {
+    let mut errors: Vec<AppError> = Vec::new();
+    let links = operations.operations().filter_map(|res_op| res_op.as_ref().ok().and_then(FSOperation::link));
+    for (source, target) in links {
+        // a writable parent directory allows to replace the source
+        for path in source.ancestors().take_while(|p| p.starts_with(source_root)) {
+            let mode = fs.mode(path).map_err(io_error(path))?;
+            if mode & 0o022 != 0 {
+                errors.push(AppError::ModeError {
+                    source: ErrorPath::from(path),
+                    target: ErrorPath::from(target),
+                    cause: format!("mode {:04o} is writable by other users while target is system-owned", mode)
+                });
+            }
+        }
+        if let Some(path) = foreign_writable_source(fs, source_root, target_owner, source) {
+            warn!("Root-owned target {} links to {} writable by its owner", target.display(), path.display());
+        }
+    }
+    Ok(errors)
+}
+
+/// First path among the linked `source` file and its writable ancestors (in the `source_root`
+/// directory) owned by another user than `target_owner`
+pub(crate) fn foreign_writable_source<'p>(fs: &dyn FileSystem, source_root: &Path, target_owner: u32, source: &'p Path) -> Option<&'p Path> {
+    source.ancestors()
+        .take_while(|p| p.starts_with(source_root))
+        .filter(|p| *p == source || fs.mode(p).map(|mode| mode & 0o200 != 0).unwrap_or(false))
+        .find(|p| fs.owner(p).map(|(uid, _)| uid != target_owner).unwrap_or(false))
+}
+
+#[cfg(test)]
+mod test_system_target {
+    use super::*;
+    use filesystem::MemoryFileSystem;
+    use stower::Stower;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn system_source() -> Arc<MemoryFileSystem> {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/app/.rstow", b"symlink_current_dir = false\nignore_files = []").unwrap();
+        fs.add_file("/source/app/app.conf", b"").unwrap();
+        fs.add_file("/source/file.txt", b"file").unwrap();
+        fs.add_dir("/target").unwrap();
+        fs.set_owner(Path::new("/target"), Some(0), Some(0)).unwrap();
+        Arc::new(fs)
+    }
+
+    #[test]
+    fn test_linked_source_errors() {
+        let fs = system_source();
+        let source_root = Path::new("/source");
+        let mut operations = OperationGraph::new();
+        operations.push(Ok(FSOperation::CreateSymlink { source: PathBuf::from("/source/app/app.conf"), target: PathBuf::from("/target/app/app.conf") }));
+
+        assert!(linked_source_errors(fs.as_ref(), source_root, 0, &operations).unwrap().is_empty());
+        fs.set_mode(Path::new("/source/app"), 0o775).unwrap();
+        match linked_source_errors(fs.as_ref(), source_root, 0, &operations).unwrap().as_slice() {
+            [AppError::ModeError { source, target, cause }] => {
+                assert_eq!(source.as_path(), Path::new("/source/app"));
+                assert_eq!(target.as_path(), Path::new("/target/app/app.conf"));
+                assert_eq!(cause, "mode 0775 is writable by other users while target is system-owned");
+            },
+            errors => panic!("mode error expected : {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn test_foreign_writable_source() {
+        let fs = system_source();
+        let source_root = Path::new("/source");
+        let source = Path::new("/source/app/app.conf");
+
+        assert_eq!(foreign_writable_source(fs.as_ref(), source_root, 0, source), Some(source));
+        assert_eq!(foreign_writable_source(fs.as_ref(), source_root, 1000, source), None);
+        fs.set_owner(source, Some(0), Some(0)).unwrap();
+        assert_eq!(foreign_writable_source(fs.as_ref(), source_root, 0, source), Some(Path::new("/source/app")));
+        // read-only directories can't be changed by their owner
+        fs.set_mode(Path::new("/source/app"), 0o555).unwrap();
+        fs.set_mode(Path::new("/source"), 0o555).unwrap();
+        assert_eq!(foreign_writable_source(fs.as_ref(), source_root, 0, source), None);
+    }
+
+    #[test]
+    fn test_system_target() {
+        let fs = system_source();
+        let stower = Stower::new("/source", "/target").owner(0).group(10).filesystem(fs.clone());
+
+        // the mode error is reported on the target link
+        fs.set_mode(Path::new("/source/app/app.conf"), 0o666).unwrap();
+        let plan = stower.plan().unwrap();
+        let errors: Vec<&Path> = plan.errors().filter_map(|e| e.path()).collect();
+        assert_eq!(errors, vec![Path::new("/target/app/app.conf")]);
+        assert!(plan.errors().any(|e| e.reason().starts_with("Source /source/app/app.conf")));
+
+        // created directories get the owner options, links keep the current user
+        fs.set_mode(Path::new("/source/app/app.conf"), 0o644).unwrap();
+        let plan = stower.plan().unwrap();
+        let operations: Vec<FSOperation> = plan.operations().map(|op| op.clone().unwrap()).collect();
+        assert_eq!(operations, vec![
+            FSOperation::CreateDir(PathBuf::from("/target/app")),
+            FSOperation::SetOwner { path: PathBuf::from("/target/app"), uid: Some(0), gid: Some(10) },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/app/app.conf"), target: PathBuf::from("/target/app/app.conf") },
+            FSOperation::CreateSymlink { source: PathBuf::from("/source/file.txt"), target: PathBuf::from("/target/file.txt") },
+        ]);
+        assert!(plan.apply().is_empty());
+        assert_eq!(fs.owner(Path::new("/target/app")).unwrap(), (0, 10));
+        assert_eq!(fs.owner(Path::new("/target/file.txt")).unwrap(), (1000, 1000));
+
+        // sources of replaced links are checked as well
+        fs.add_file("/elsewhere/app.conf", b"").unwrap();
+        fs.remove_file(Path::new("/target/app/app.conf")).unwrap();
+        fs.symlink(Path::new("/elsewhere/app.conf"), Path::new("/target/app/app.conf")).unwrap();
+        fs.set_mode(Path::new("/source/app/app.conf"), 0o666).unwrap();
+        let plan = stower.force(true).plan().unwrap();
+        assert!(plan.operations().any(|op| op.as_ref().ok() == Some(&FSOperation::ReplaceSymlink { source: PathBuf::from("/source/app/app.conf"), target: PathBuf::from("/target/app/app.conf") })));
+        assert!(plan.errors().any(|e| matches!(e, AppError::ModeError { .. }) && e.path() == Some(Path::new("/target/app/app.conf"))));
+    }
+}
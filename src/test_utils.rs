@@ -1,7 +1,9 @@
 use super::*;
 use std::fs::*;
 use std::panic;
+use std::path::{Path, PathBuf};
 use std::io::Result;
+use std::sync::Arc;
 
 pub const TESTS_DIRECTORY: &'static str = "/tmp/rstow-tests";
 
@@ -28,17 +30,26 @@ pub fn clear_directory(path: &Path) -> Result<()> {
 pub fn add_file_to(name: &str, path: &Path) -> Result<PathBuf> {
     let file_path = path.join(name);
     println!("Add file {} in {} directory", file_path.display(), path.display());
-    File::create(file_path.as_path());
+    File::create(file_path.as_path())?;
     Ok(file_path)
 }
 
 pub fn add_directory_to(name: &str, path: &Path) -> Result<PathBuf> {
     let dir_path = path.join(name);
     println!("Add directory {} in {} directory", dir_path.display(), path.display());
-    create_dir_all(dir_path.as_path());
+    create_dir_all(dir_path.as_path())?;
     Ok(dir_path)
 }
 
+/// In-memory `/source` directory with a file and a directory, and an empty `/target` directory
+pub fn memory_source() -> Arc<MemoryFileSystem> {
+    let fs = MemoryFileSystem::new();
+    fs.add_file("/source/file.txt", b"file").unwrap();
+    fs.add_file("/source/dir/child.txt", b"child").unwrap();
+    fs.add_dir("/target").unwrap();
+    Arc::new(fs)
+}
+
 pub fn with_test_directories(name: &str, test: impl FnOnce(&PathBuf, &PathBuf) -> () + std::panic::UnwindSafe) -> Result<()>  {
 
     let test_dir = PathBuf::from(TESTS_DIRECTORY.to_owned() + "/" + name);
@@ -1,22 +1,24 @@
 use im::vector::*;
-use failure::Error;
 
 use std::result::Result;
-use std::path::{Path, PathBuf};
-use std::collections::LinkedList;
+use std::path::Path;
 
 use fileutils::*;
 use errors::*;
 use operations::FSOperation;
 use operations::TraversOperation;
-
-pub(crate) fn unstow_path<'a>(source_path: &'a Path, target_path: &'a Path, operations: &'a mut Vector<FSOperation>) -> Result<TraversOperation, AppError> {
-    let target_is_directory = source_path.is_dir();
-    let target_exist = target_path.exists();
-    let target_is_symlink = is_symlink(target_path);
-    let is_valid_symlink = check_symlink(target_path, source_path);
+use filesystem::FileSystem;
+
+/// Find operations to un-stow `source_path` from `target_path` : remove the symbolic link
+/// and restore the backup if any. Operations are added to `operations`.
+pub fn unstow_path<'a>(fs: &dyn FileSystem, source_path: &'a Path, target_path: &'a Path, operations: &'a mut Vector<FSOperation>) -> Result<TraversOperation, AppError> {
+    let target_is_symlink = is_symlink(fs, target_path);
+    // a link to a removed source path is dangling but still has to be un-stowed
+    let is_removed_source_link = !fs.exists(source_path) && fs.read_link(target_path).map(|link| link == source_path).unwrap_or(false);
+    let target_exist = fs.exists(target_path) || is_removed_source_link;
+    let is_valid_symlink = check_symlink(fs, target_path, source_path) || is_removed_source_link;
     let backup_path = build_backup_path(target_path)?;
-    let backup_exist = backup_path.exists();
+    let backup_exist = fs.exists(backup_path.as_path());
 
     if !target_exist || !target_is_symlink || !is_valid_symlink {
 
@@ -33,7 +35,7 @@ pub(crate) fn unstow_path<'a>(source_path: &'a Path, target_path: &'a Path, oper
     }
 
     //remove symlink
-    operations.push_back(FSOperation::Delete(target_path.to_path_buf()));
+    operations.push_back(FSOperation::DeleteSymlink(target_path.to_path_buf()));
 
     //restore backup if exist
     if backup_exist {
@@ -43,30 +45,33 @@ pub(crate) fn unstow_path<'a>(source_path: &'a Path, target_path: &'a Path, oper
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_asref)]
 mod test_unstow {
     use super::*;
     use test_utils::*;
+    use std::path::PathBuf;
     use std::borrow::BorrowMut;
     use std::fs::*;
+    use filesystem::RealFileSystem;
 
     #[test]
     fn test_valid_link_file() {
         with_test_directories("unstow_test_valid_link_file".as_ref(), |source: &PathBuf, target: &PathBuf| {
             let source_file = add_file_to("file.txt", source.as_path()).unwrap();
             let target_file = target.join("file.txt");
-            create_symlink(source_file.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
 
             let mut iter = operations.iter();
-            assert_eq!(iter.next().unwrap(), &FSOperation::Delete(target_file.to_path_buf()));
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteSymlink(target_file.to_path_buf()));
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -76,20 +81,20 @@ mod test_unstow {
             let target_file = target.join("file.txt");
             let backup_target_file = add_file_to("file.txt.backup", target.as_path()).unwrap();
 
-            create_symlink(source_file.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
 
             let mut iter = operations.iter();
-            assert_eq!(iter.next().unwrap(), &FSOperation::Delete(target_file.to_path_buf()));
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteSymlink(target_file.to_path_buf()));
             assert_eq!(iter.next().unwrap(), &FSOperation::Restore {backup: backup_target_file.to_path_buf(), target: target_file.to_path_buf()});
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -101,10 +106,10 @@ mod test_unstow {
             let other_source_dir: PathBuf = source.parent().unwrap().to_path_buf().join("somewhere");
             create_dir_all(other_source_dir.as_path()).unwrap();
             let other_source = add_file_to("file.txt", other_source_dir.as_path()).unwrap();
-            create_symlink(other_source.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, other_source.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
@@ -113,7 +118,7 @@ mod test_unstow {
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::Nothing { path: target_file.to_path_buf(), cause: "Target symlink invalid".to_owned() });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -123,7 +128,7 @@ mod test_unstow {
             let target_file = add_file_to("file.txt", target.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
@@ -132,7 +137,7 @@ mod test_unstow {
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::Nothing { path: target_file.to_path_buf(), cause: "Target not a symlink".to_owned() });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -142,7 +147,7 @@ mod test_unstow {
             let target_file = target.join("file.txt");
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
@@ -151,7 +156,25 @@ mod test_unstow {
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::Nothing { path: target_file.to_path_buf(), cause: "Target not found".to_owned() });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_removed_source_link_file() {
+        with_test_directories("unstow_test_removed_source_link_file", |source: &PathBuf, target: &PathBuf| {
+            let source_file = add_file_to("file.txt", source.as_path()).unwrap();
+            let target_file = target.join("file.txt");
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
+            remove_file(source_file.as_path()).unwrap();
+
+            let mut operations: Vector<FSOperation> = Vector::new();
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+
+            assert!(result.is_ok());
+            let mut iter = operations.iter();
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteSymlink(target_file.to_path_buf()));
+            assert_eq!(iter.next(), None);
+        }).unwrap();
     }
 
     #[test]
@@ -159,19 +182,19 @@ mod test_unstow {
         with_test_directories("unstow_test_valid_link_directory".as_ref(), |source: &PathBuf, target: &PathBuf| {
             let source_file = add_directory_to("subdir", source.as_path()).unwrap();
             let target_file = target.join("subdir");
-            create_symlink(source_file.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
 
             let mut iter = operations.iter();
-            assert_eq!(iter.next().unwrap(), &FSOperation::Delete(target_file.to_path_buf()));
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteSymlink(target_file.to_path_buf()));
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -181,20 +204,20 @@ mod test_unstow {
             let target_file = target.join("subdir");
             let backup_target_file = add_directory_to("subdir.backup", target.as_path()).unwrap();
 
-            create_symlink(source_file.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, source_file.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), TraversOperation::Continue);
 
             let mut iter = operations.iter();
-            assert_eq!(iter.next().unwrap(), &FSOperation::Delete(target_file.to_path_buf()));
+            assert_eq!(iter.next().unwrap(), &FSOperation::DeleteSymlink(target_file.to_path_buf()));
             assert_eq!(iter.next().unwrap(), &FSOperation::Restore {backup: backup_target_file.to_path_buf(), target: target_file.to_path_buf()});
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
     #[test]
@@ -206,10 +229,10 @@ mod test_unstow {
             let other_source_dir: PathBuf = source.parent().unwrap().to_path_buf().join("somewhere");
             create_dir_all(other_source_dir.as_path()).unwrap();
             let other_source = add_directory_to("subdir", other_source_dir.as_path()).unwrap();
-            create_symlink(other_source.as_path(), target_file.as_path()).unwrap();
+            create_symlink(&RealFileSystem, other_source.as_path(), target_file.as_path()).unwrap();
 
             let mut operations: Vector<FSOperation> = Vector::new();
-            let result = unstow_path(source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
+            let result = unstow_path(&RealFileSystem, source_file.as_path(), target_file.as_path(),  operations.borrow_mut());
 
             // nothing to do, continue traversing
             assert!(result.is_ok());
@@ -218,7 +241,7 @@ mod test_unstow {
             let mut iter = operations.iter();
             assert_eq!(iter.next().unwrap(), &FSOperation::Nothing { path: target_file.to_path_buf(), cause: "Target symlink invalid".to_owned() });
             assert_eq!(iter.next(), None);
-        });
+        }).unwrap();
     }
 
 }
@@ -0,0 +1,185 @@
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use errors::{io_error, AppError, ErrorPath};
+use filesystem::FileSystem;
+use report::Report;
+
+/// Difference found between a target path and the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    /// Source path deployed to `target`, `None` for a target file not part of the source
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    pub cause: String,
+    /// Unified diff from the source file to a modified target copy (text files only)
+    pub diff: Option<String>,
+}
+
+impl Drift {
+    pub(crate) fn new(source: Option<&Path>, target: &Path, cause: &str) -> Drift {
+        Drift { source: source.map(|s| s.to_path_buf()), target: target.to_path_buf(), cause: cause.to_string(), diff: None }
+    }
+}
+
+/// Drifts found by `Stower::verify` and errors preventing to verify some paths
+#[derive(Debug, Clone, Default)]
+pub struct Verification {
+    drifts: Vec<Drift>,
+    errors: Vec<AppError>,
+}
+
+impl Verification {
+    pub fn drifts(&self) -> &[Drift] {
+        &self.drifts
+    }
+
+    pub fn errors(&self) -> &[AppError] {
+        &self.errors
+    }
+
+    /// Report with a conflict for each drift, followed by errors
+    pub fn report(&self) -> Report {
+        let mut report = Report::new();
+        report.add_all(self.drifts.iter().map(|drift| AppError::DriftError {
+            path: ErrorPath::from(drift.target.as_path()),
+            cause: drift.cause.clone(),
+        }));
+        report.add_all(self.errors.iter().cloned());
+        report
+    }
+
+    pub(crate) fn push(&mut self, drift: Drift) {
+        self.drifts.push(drift);
+    }
+
+    pub(crate) fn push_error(&mut self, error: AppError) {
+        self.errors.push(error);
+    }
+}
+
+/// Compare a deployed `target` file with its `source` file : it must be the same file (symbolic
+/// or hard link) or have the same content hash. `expected` replaces the source content when the
+/// target is not a copy of it (decrypted files), no diff is given in that case.
+pub(crate) fn compare_file(fs: &dyn FileSystem, source: &Path, target: &Path, expected: Option<&[u8]>) -> Result<Option<Drift>, AppError> {
+    if !fs.exists(target) {
+        let cause = if fs.is_symlink(target) { "dangling symbolic link on target" } else { "missing on target" };
+        return Ok(Some(Drift::new(Some(source), target, cause)));
+    }
+    if fs.is_dir(target) {
+        return Ok(Some(Drift::new(Some(source), target, "target is a directory")));
+    }
+    if expected.is_none() && fs.is_same_file(source, target) {
+        return Ok(None);
+    }
+
+    let target_content = fs.read(target).map_err(io_error(target))?;
+    let source_content = match expected {
+        Some(expected) => expected.to_vec(),
+        None => fs.read(source).map_err(io_error(source))?,
+    };
+    if content_hash(&source_content) == content_hash(&target_content) {
+        return Ok(None);
+    }
+
+    let cause = match fs.read_link(target) {
+        Ok(link) => format!("linked to {} with a different content", link.display()),
+        Err(_) => "content differs from source".to_string(),
+    };
+    let mut drift = Drift::new(Some(source), target, cause.as_str());
+    if expected.is_none() {
+        drift.diff = unified_diff(source, target, &source_content, &target_content);
+    }
+    Ok(Some(drift))
+}
+
+/// Unified diff from `old` content to `new` content, `None` if one of them is not UTF-8 text
+pub(crate) fn unified_diff(old_path: &Path, new_path: &Path, old: &[u8], new: &[u8]) -> Option<String> {
+    let old = std::str::from_utf8(old).ok()?;
+    let new = std::str::from_utf8(new).ok()?;
+    Some(TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&old_path.display().to_string(), &new_path.display().to_string())
+        .to_string())
+}
+
+fn content_hash(content: &[u8]) -> Vec<u8> {
+    Sha256::digest(content).to_vec()
+}
+
+#[cfg(test)]
+mod test_verify {
+    use super::*;
+    use filesystem::MemoryFileSystem;
+    use stower::Stower;
+    use test_utils::memory_source;
+
+    #[test]
+    fn test_compare_file() {
+        let fs = MemoryFileSystem::new();
+        fs.add_file("/source/a", b"one\ntwo\n").unwrap();
+        fs.add_file("/target/copy", b"one\ntwo\n").unwrap();
+        fs.add_file("/target/modified", b"one\n2\n").unwrap();
+        fs.symlink(Path::new("/source/a"), Path::new("/target/link")).unwrap();
+        fs.symlink(Path::new("/source/missing"), Path::new("/target/dangling")).unwrap();
+        let source = Path::new("/source/a");
+
+        assert_eq!(compare_file(&fs, source, Path::new("/target/link"), None).unwrap(), None);
+        assert_eq!(compare_file(&fs, source, Path::new("/target/copy"), None).unwrap(), None);
+        assert_eq!(compare_file(&fs, source, Path::new("/target/missing"), None).unwrap().unwrap().cause, "missing on target");
+        assert_eq!(compare_file(&fs, source, Path::new("/target/dangling"), None).unwrap().unwrap().cause, "dangling symbolic link on target");
+        assert_eq!(compare_file(&fs, source, Path::new("/target"), None).unwrap().unwrap().cause, "target is a directory");
+
+        let drift = compare_file(&fs, source, Path::new("/target/modified"), None).unwrap().unwrap();
+        assert_eq!(drift.cause, "content differs from source");
+        assert_eq!(drift.diff.unwrap(), "--- /source/a\n+++ /target/modified\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n");
+
+        // decrypted content isn't shown
+        let drift = compare_file(&fs, source, Path::new("/target/modified"), Some(b"secret\n")).unwrap().unwrap();
+        assert_eq!(drift.diff, None);
+        assert_eq!(compare_file(&fs, source, Path::new("/target/modified"), Some(b"one\n2\n")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify() {
+        let fs = memory_source();
+        fs.add_file("/source/conf/same.ini", b"a = 1\n").unwrap();
+        fs.add_file("/source/conf/app.ini", b"a = 1\nb = 2\n").unwrap();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+        assert!(stower.plan().unwrap().apply().is_empty());
+        assert!(stower.verify().unwrap().drifts().is_empty());
+
+        // partially folded directory with copies
+        fs.remove_dir_all(Path::new("/target/conf")).unwrap();
+        fs.add_file("/target/conf/same.ini", b"a = 1\n").unwrap();
+        fs.add_file("/target/conf/app.ini", b"a = 1\nb = 3\n").unwrap();
+        fs.remove_file(Path::new("/target/file.txt")).unwrap();
+
+        let verification = stower.verify().unwrap();
+        let drifts: Vec<(&Path, &str)> = verification.drifts().iter().map(|d| (d.target.as_path(), d.cause.as_str())).collect();
+        assert_eq!(drifts, vec![
+            (Path::new("/target/conf/app.ini"), "content differs from source"),
+            (Path::new("/target/file.txt"), "missing on target"),
+        ]);
+        assert!(verification.drifts()[0].diff.as_ref().unwrap().contains("-b = 2\n+b = 3\n"));
+        let report = verification.report();
+        assert!(report.errors().iter().all(|e| matches!(e, AppError::DriftError { .. })));
+        assert_eq!(report.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_verify_linked_dir_with_ignored_files() {
+        let fs = memory_source();
+        fs.add_file("/source/dir/README.md", b"readme").unwrap();
+        fs.add_file("/source/dir/.child.txt.swp", b"").unwrap();
+        let stower = Stower::new("/source", "/target").filesystem(fs.clone());
+        assert!(stower.plan().unwrap().apply().is_empty());
+        assert!(fs.is_symlink(Path::new("/target/dir")));
+
+        // ignored source files are visible through the link, they are part of the source
+        assert!(stower.verify().unwrap().drifts().is_empty());
+    }
+}
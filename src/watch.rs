@@ -0,0 +1,139 @@
+use quicli::prelude::*;
+use inotify::{Events, EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::thread;
+use std::time::Duration;
+
+use errors::*;
+use filesystem::{FileSystem, RealFileSystem};
+use interpreters::{FileSystemInterpreter, Interpreter};
+use operations::FSOperation;
+use report::Report;
+use stower::Stower;
+
+/// Watch the source directory of `stower` and stow created paths (or un-stow removed ones)
+/// once no change happened during `debounce`. With `Stower::lock_timeout`, the target is locked
+/// while changes are planned and applied, changes are kept and planned again while another
+/// run holds the lock. Never returns unless an error occurs.
+pub fn watch(stower: &Stower, debounce: Duration) -> Result<(), AppError> {
+    let source = RealFileSystem.canonicalize(stower.source()).map_err(|e| AppError::InvalidPath {
+        path: ErrorPath::from(stower.source()),
+        cause: format!("Unresolved absolute path ({})", e)
+    })?;
+    let interpreter = WatchInterpreter { inner: stower.interpreter() };
+
+    let mut inotify = Inotify::init().map_err(io_error(source.as_path()))?;
+    let mut watches: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    add_watches(&mut inotify, &mut watches, source.as_path())?;
+    println!("WATCH : watching {}", source.display());
+
+    let mut buffer = [0u8; 4096];
+    let mut changes = Changes::default();
+    loop {
+        if changes.is_empty() {
+            changes.read(inotify.read_events_blocking(&mut buffer).map_err(io_error(source.as_path()))?, &mut watches);
+        }
+
+        // wait until the source directory is quiet
+        loop {
+            thread::sleep(debounce);
+            match inotify.read_events(&mut buffer) {
+                Ok(events) => changes.read(events, &mut watches),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(io_error(source.as_path())(e)),
+            }
+        }
+
+        for path in changes.paths.iter().filter(|p| p.is_dir()) {
+            add_watches(&mut inotify, &mut watches, path.as_path())?;
+        }
+
+        let plan = if changes.overflow {
+            warn!("Too many changes in {}, plan the whole directory", source.display());
+            stower.plan()
+        } else {
+            let paths: Vec<&PathBuf> = changes.paths.iter().collect();
+            stower.plan_paths(&paths)
+        };
+        let report = match plan {
+            Ok(plan) => plan.run(&interpreter),
+            Err(e @ AppError::LockError { .. }) => {
+                warn!("Target is locked, {} changes will be planned again", changes.paths.len());
+                let mut report = Report::new();
+                report.add(e);
+                report.print();
+                continue;
+            },
+            Err(e) => {
+                let mut report = Report::new();
+                report.add(e);
+                report
+            }
+        };
+        changes = Changes::default();
+        report.print();
+    }
+}
+
+/// Source paths changed since the last plan
+#[derive(Debug, Default)]
+struct Changes {
+    paths: BTreeSet<PathBuf>,
+    // some events were lost
+    overflow: bool,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty() && !self.overflow
+    }
+
+    fn read(&mut self, events: Events, watches: &mut HashMap<WatchDescriptor, PathBuf>) {
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                self.overflow = true;
+            } else if event.mask.contains(EventMask::IGNORED) {
+                watches.remove(&event.wd);
+            } else if let (Some(directory), Some(name)) = (watches.get(&event.wd), event.name) {
+                let path = directory.join(name);
+                debug!("Change {:?} on {}", event.mask, path.display());
+                self.paths.insert(path);
+            }
+        }
+    }
+}
+
+/// Watch `directory` and its sub directories (inotify watches are not recursive)
+fn add_watches(inotify: &mut Inotify, watches: &mut HashMap<WatchDescriptor, PathBuf>, directory: &Path) -> Result<(), AppError> {
+    let mask = WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::ONLYDIR | WatchMask::DONT_FOLLOW;
+    let descriptor = inotify.watches().add(directory, mask).map_err(io_error(directory))?;
+    watches.insert(descriptor, directory.to_path_buf());
+
+    for path in RealFileSystem.read_dir(directory).map_err(io_error(directory))? {
+        if RealFileSystem.is_dir(path.as_path()) && !RealFileSystem.is_symlink(path.as_path()) {
+            add_watches(inotify, watches, path.as_path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Apply operations on filesystem and log them
+#[derive(Debug)]
+struct WatchInterpreter {
+    inner: FileSystemInterpreter,
+}
+
+impl Interpreter for WatchInterpreter {
+    fn interpret(&self, op: &FSOperation) -> Result<(), AppError> {
+        self.inner.interpret(op)?;
+        match op {
+            FSOperation::Nothing {..} => (),
+            _ => println!("WATCH : {}", op),
+        }
+        Ok(())
+    }
+}